@@ -0,0 +1,30 @@
+// Coverage-guided version of the differential test in src/fuzz.rs: every
+// input is split into quirks, keys and a ROM, run on the real interpreter and
+// the reference one, and any difference between them is a crash.
+//
+//     cargo +nightly fuzz run differential
+//
+// The emulator is a binary crate, so the modules it needs are pulled in by
+// path. Most of what's in them goes unused here.
+
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/chip8.rs"]
+mod chip8;
+#[path = "../../src/hexes.rs"]
+mod hexes;
+#[path = "../../src/instruction.rs"]
+mod instruction;
+#[path = "../../src/translator.rs"]
+mod translator;
+#[path = "../../src/fuzz.rs"]
+mod fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(e) = fuzz::run_input(data) {
+        panic!("{}", e);
+    }
+});
@@ -0,0 +1,206 @@
+// Where the beeper's sound goes: a sound card, a WAV file or nowhere.
+
+use std::{error::Error, fmt, io, path::{Path, PathBuf}, str::FromStr};
+
+use crate::beep::{Beep, ToneGenerator, ToneSettings};
+use crate::wav::{self, WavWriter};
+
+pub const WAV_SAMPLE_RATE: u32 = 44100;
+const SAMPLES_PER_FRAME: u32 = WAV_SAMPLE_RATE / 60;
+
+pub trait AudioBackend {
+    fn name(&self) -> String;
+
+    // Plays the sound for one emulated frame.
+    fn push_frame(&mut self, playing: bool, run_speed: f32) -> Result<(), Box<dyn Error>>;
+
+    fn set_tone(&mut self, settings: ToneSettings) -> Result<(), Box<dyn Error>>;
+}
+
+impl AudioBackend for Beep {
+    fn name(&self) -> String {
+        "sound card".to_string()
+    }
+
+    fn push_frame(&mut self, playing: bool, run_speed: f32) -> Result<(), Box<dyn Error>> {
+        Ok(Beep::push_frame(self, playing, run_speed)?)
+    }
+
+    fn set_tone(&mut self, settings: ToneSettings) -> Result<(), Box<dyn Error>> {
+        Ok(Beep::set_tone(self, settings)?)
+    }
+}
+
+// Throws the sound away.
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn name(&self) -> String {
+        "none".to_string()
+    }
+
+    fn push_frame(&mut self, _playing: bool, _run_speed: f32) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn set_tone(&mut self, _settings: ToneSettings) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// Writes the sound to a WAV file. Every frame lasts 1/60 s of emulated
+// time, whatever speed the emulator is running at.
+pub struct WavAudio {
+    path: PathBuf,
+    writer: WavWriter,
+    tone: ToneGenerator
+}
+
+impl WavAudio {
+    pub fn new(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let writer = WavWriter::create(&path, WAV_SAMPLE_RATE)?;
+        Ok(WavAudio {
+            path,
+            writer,
+            tone: ToneGenerator::new(WAV_SAMPLE_RATE, ToneSettings::default())
+        })
+    }
+}
+
+impl AudioBackend for WavAudio {
+    fn name(&self) -> String {
+        format!("WAV file {}", self.path.display())
+    }
+
+    fn push_frame(&mut self, playing: bool, _run_speed: f32) -> Result<(), Box<dyn Error>> {
+        self.tone.set_playing(playing);
+        let samples: Vec<f32> = (0..SAMPLES_PER_FRAME).map(|_| self.tone.next_sample()).collect();
+        self.writer.write_samples(&samples)?;
+        Ok(())
+    }
+
+    fn set_tone(&mut self, settings: ToneSettings) -> Result<(), Box<dyn Error>> {
+        self.tone.settings = settings;
+        Ok(())
+    }
+}
+
+// Keeps the sound state of every frame, to be checked or exported later.
+#[derive(Default)]
+pub struct CaptureAudio {
+    pub frames: Vec<bool>
+}
+
+impl AudioBackend for CaptureAudio {
+    fn name(&self) -> String {
+        "capture".to_string()
+    }
+
+    fn push_frame(&mut self, playing: bool, _run_speed: f32) -> Result<(), Box<dyn Error>> {
+        self.frames.push(playing);
+        Ok(())
+    }
+
+    fn set_tone(&mut self, _settings: ToneSettings) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// Turns per-frame sound states into samples, the same way they're played live.
+pub fn synthesize(sound: &[bool], tone: ToneSettings) -> Vec<f32> {
+    let mut generator = ToneGenerator::new(WAV_SAMPLE_RATE, tone);
+    let mut samples = Vec::with_capacity(sound.len() * SAMPLES_PER_FRAME as usize);
+    for &playing in sound {
+        generator.set_playing(playing);
+        for _ in 0..SAMPLES_PER_FRAME {
+            samples.push(generator.next_sample());
+        }
+    }
+    samples
+}
+
+// Saves per-frame sound states as a WAV file.
+pub fn export_wav(sound: &[bool], tone: ToneSettings, path: &Path) -> io::Result<()> {
+    wav::write_wav(path, WAV_SAMPLE_RATE, &synthesize(sound, tone))
+}
+
+// Audio backend picked on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioOutput {
+    // Sound card if there is one, otherwise nothing
+    Auto,
+    Cpal,
+    Null,
+    Wav(PathBuf)
+}
+
+impl FromStr for AudioOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(AudioOutput::Auto),
+            "cpal" => Ok(AudioOutput::Cpal),
+            "none" => Ok(AudioOutput::Null),
+            _ => match s.strip_prefix("wav:") {
+                Some(path) if !path.is_empty() => Ok(AudioOutput::Wav(PathBuf::from(path))),
+                _ => Err(format!("Unknown audio output '{}', expected auto, cpal, none or wav:<file>", s)),
+            }
+        }
+    }
+}
+
+impl fmt::Display for AudioOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioOutput::Auto => write!(f, "auto"),
+            AudioOutput::Cpal => write!(f, "cpal"),
+            AudioOutput::Null => write!(f, "none"),
+            AudioOutput::Wav(path) => write!(f, "wav:{}", path.display()),
+        }
+    }
+}
+
+// Opens the requested backend. If that fails, sound is turned off and
+// the error is returned alongside so it can be shown to the user.
+pub fn open(output: &AudioOutput) -> (Box<dyn AudioBackend>, Option<String>) {
+    let result: Result<Box<dyn AudioBackend>, Box<dyn Error>> = match output {
+        AudioOutput::Auto | AudioOutput::Cpal => Beep::new().map(|beep| Box::new(beep) as Box<dyn AudioBackend>),
+        AudioOutput::Null => Ok(Box::new(NullAudio)),
+        AudioOutput::Wav(path) => WavAudio::new(path.clone()).map(|wav| Box::new(wav) as Box<dyn AudioBackend>),
+    };
+    match result {
+        Ok(backend) => (backend, None),
+        Err(e) if *output == AudioOutput::Auto => (Box::new(NullAudio), Some(format!("No sound card available, sound is off: {}", e))),
+        Err(e) => (Box::new(NullAudio), Some(format!("Couldn't open audio output {}: {}", output, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::{self, DEFAULT_SEED};
+
+    #[test]
+    fn sound_timer_plays_for_its_value_in_frames() {
+        for frames in [1, 5, 60] {
+            // ST = V0, then loop forever
+            let rom = [0x60, frames, 0xF0, 0x18, 0x12, 0x04];
+            let mut chip8 = headless::new_machine(&rom, DEFAULT_SEED).unwrap();
+            let mut capture = CaptureAudio::default();
+            headless::run_frames(&mut chip8, 100, 8, 0, &mut capture).unwrap();
+            let sounding: Vec<usize> = (0..capture.frames.len()).filter(|&i| capture.frames[i]).collect();
+            assert_eq!(sounding.len(), frames as usize, "ST = {}", frames);
+            assert_eq!(sounding, (0..frames as usize).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn synthesizes_a_frame_of_samples_per_frame() {
+        let samples = synthesize(&[false, true, true, false], ToneSettings::default());
+        assert_eq!(samples.len(), 4 * SAMPLES_PER_FRAME as usize);
+        let frame = |i: usize| &samples[i * SAMPLES_PER_FRAME as usize..(i + 1) * SAMPLES_PER_FRAME as usize];
+        assert!(frame(0).iter().all(|&sample| sample == 0.0));
+        assert!(frame(1).iter().any(|&sample| sample != 0.0));
+    }
+}
@@ -1,13 +1,61 @@
-use std::{error::Error, f32::consts::PI, thread, sync::mpsc::{self, Sender}};
+use std::{error::Error, f32::consts::PI, thread, collections::VecDeque, sync::mpsc::{self, Sender, Receiver}};
 
 use cpal::{traits::{HostTrait, DeviceTrait, StreamTrait}, SampleFormat, Sample, Device, StreamConfig, Stream, BuildStreamError};
 
+// How long the tone takes to fade in or out, to avoid clicks.
+const FADE_TIME: f32 = 0.005;
+// Audio queued up before playback starts, to ride out scheduling jitter.
+const BUFFER_TIME: f32 = 0.03;
+// Most audio allowed to queue up. Past this, old frames are dropped to catch up.
+const MAX_QUEUED_TIME: f32 = 0.2;
+// Frames always kept, however long they are, so slow motion still has sound.
+const MIN_QUEUED_FRAMES: usize = 2;
+
 pub enum BeepInstructions {
-    Play,
-    Pause,
+    // Sound state for one emulated frame, and how many seconds of audio it lasts
+    Frame(bool, f32),
+    Tone(ToneSettings),
     Stop
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 4] = [Waveform::Square, Waveform::Sine, Waveform::Triangle, Waveform::Noise];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Waveform::Square => "Square",
+            Waveform::Sine => "Sine",
+            Waveform::Triangle => "Triangle",
+            Waveform::Noise => "Noise",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneSettings {
+    pub pitch: f32,     // in Hz
+    pub waveform: Waveform,
+    pub volume: f32     // from 0 to 1
+}
+
+impl Default for ToneSettings {
+    fn default() -> Self {
+        Self {
+            pitch: 440.0,
+            waveform: Waveform::Sine,
+            volume: 0.5
+        }
+    }
+}
+
 pub struct Beep {
     sender: Sender<BeepInstructions>
 }
@@ -22,75 +70,182 @@ impl Beep {
         let sample_format = supported_config.sample_format();
 
         let (send, recv) = mpsc::channel::<BeepInstructions>();
+        let (ready_send, ready_recv) = mpsc::channel::<Result<(), String>>();
 
         thread::spawn(move || {
+            // the stream keeps running the whole time, and fades the tone in and out itself
+            let (tone_send, tone_recv) = mpsc::channel::<BeepInstructions>();
             let stream = match sample_format {
-                SampleFormat::F32 => Self::create_stream::<f32>(&device, &supported_config.into()),
-                SampleFormat::I16 => Self::create_stream::<i16>(&device, &supported_config.into()),
-                SampleFormat::U16 => Self::create_stream::<u16>(&device, &supported_config.into()),
-            }.unwrap();
+                SampleFormat::F32 => Self::create_stream::<f32>(&device, &supported_config.into(), tone_recv),
+                SampleFormat::I16 => Self::create_stream::<i16>(&device, &supported_config.into(), tone_recv),
+                SampleFormat::U16 => Self::create_stream::<u16>(&device, &supported_config.into(), tone_recv),
+            };
+            let stream = match stream.map_err(|e| e.to_string()).and_then(|s| s.play().map(|_| s).map_err(|e| e.to_string())) {
+                Ok(stream) => {
+                    let _ = ready_send.send(Ok(()));
+                    stream
+                },
+                Err(e) => {
+                    let _ = ready_send.send(Err(e));
+                    return;
+                }
+            };
 
             loop {
                 match recv.recv() {
-                    Ok(BeepInstructions::Play) => {
-                        stream.play().unwrap();
-                    },
-                    Ok(BeepInstructions::Pause) => {
-                        stream.pause().unwrap();
-                    }
-                    Ok(BeepInstructions::Stop) => {
-                        stream.pause().unwrap();
+                    Ok(BeepInstructions::Stop) | Err(_) => {
+                        let _ = stream.pause();
                         return;
+                    },
+                    Ok(instruction) => {
+                        if tone_send.send(instruction).is_err() {
+                            return;
+                        }
                     }
-                    Err(e) => eprintln!("Error in beep playback: {}", e),
                 };
             }
         });
 
+        // wait for the stream to start, so failures are reported here
+        ready_recv.recv().map_err(|_| "Audio thread stopped unexpectedly")??;
+
         Ok(Self {
             sender: send
         })
     }
 
-    pub fn play(&self) -> Result<(), mpsc::SendError<BeepInstructions>> {
-        self.sender.send(BeepInstructions::Play)
+    // Queues up the sound for an emulated frame. At run speeds other than 1x
+    // frames last shorter or longer, so the sound stays in sync with the game.
+    pub fn push_frame(&self, playing: bool, run_speed: f32) -> Result<(), mpsc::SendError<BeepInstructions>> {
+        self.sender.send(BeepInstructions::Frame(playing, 1f32 / 60f32 / run_speed))
     }
 
-    pub fn pause(&self) -> Result<(), mpsc::SendError<BeepInstructions>> {
-        self.sender.send(BeepInstructions::Pause)
+    pub fn set_tone(&self, settings: ToneSettings) -> Result<(), mpsc::SendError<BeepInstructions>> {
+        self.sender.send(BeepInstructions::Tone(settings))
     }
 
     pub fn stop(&self) -> Result<(), mpsc::SendError<BeepInstructions>> {
         self.sender.send(BeepInstructions::Stop)
     }
 
-    fn create_stream<T: Sample>(device: &Device, config: &StreamConfig) -> Result<Stream, BuildStreamError> {
-        let sample_rate = config.sample_rate.0 as f32;
+    fn create_stream<T: Sample>(device: &Device, config: &StreamConfig, recv: Receiver<BeepInstructions>) -> Result<Stream, BuildStreamError> {
         let channels = config.channels as usize;
-
-        let mut cur_time = 0f32;
-        let mut get_next = move || {
-            cur_time = (cur_time + 1f32) % sample_rate;
-            (cur_time * 880f32 * PI / sample_rate).sin()
-        };
+        let sample_rate = config.sample_rate.0 as f32;
+        let mut tone = ToneGenerator::new(config.sample_rate.0, ToneSettings::default());
+        // frames waiting to be played, as (sound on, samples left)
+        let mut frames: VecDeque<(bool, f32)> = VecDeque::new();
+        let mut queued = 0f32;
+        let mut buffering = true;
 
         device.build_output_stream(
             config,
             move |data: &mut [T], _| {
+                while let Ok(instruction) = recv.try_recv() {
+                    match instruction {
+                        BeepInstructions::Frame(playing, duration) => {
+                            let samples = duration * sample_rate;
+                            frames.push_back((playing, samples));
+                            queued += samples;
+                        },
+                        BeepInstructions::Tone(settings) => tone.settings = settings,
+                        BeepInstructions::Stop => (),
+                    }
+                }
+                while frames.len() > MIN_QUEUED_FRAMES && queued > MAX_QUEUED_TIME * sample_rate {
+                    let (_, samples) = frames.pop_front().unwrap();
+                    queued -= samples;
+                }
+                if queued >= BUFFER_TIME * sample_rate {
+                    buffering = false;
+                }
+
                 for ch in data.chunks_mut(channels) {
-                    let v = Sample::from::<f32>(&get_next());
+                    if buffering {
+                        tone.set_playing(false);
+                    } else if let Some((playing, samples)) = frames.front_mut() {
+                        tone.set_playing(*playing);
+                        *samples -= 1f32;
+                        queued -= 1f32;
+                        if *samples <= 0f32 {
+                            frames.pop_front();
+                        }
+                    } else {
+                        // ran out, so wait for the queue to fill up again
+                        tone.set_playing(false);
+                        buffering = true;
+                        queued = 0f32;
+                    }
+                    let v = Sample::from::<f32>(&tone.next_sample());
                     for sample in ch.iter_mut() {
                         *sample = v;
                     }
                 }
-            }, 
+            },
             |err| { eprintln!("Error! {}", err)}
         )
     }
 }
 
+// Generates the beep tone one sample at a time.
+pub struct ToneGenerator {
+    pub settings: ToneSettings,
+    sample_rate: f32,
+    phase: f32,         // position in the current wave cycle, from 0 to 1
+    playing: bool,
+    level: f32,         // fade in/out envelope, from 0 to 1
+    noise: u32,         // xorshift state for the noise waveform
+    noise_val: f32
+}
+
+impl ToneGenerator {
+    pub fn new(sample_rate: u32, settings: ToneSettings) -> Self {
+        ToneGenerator {
+            settings,
+            sample_rate: sample_rate as f32,
+            phase: 0f32,
+            playing: false,
+            level: 0f32,
+            noise: 0x2545F491,
+            noise_val: 0f32
+        }
+    }
+
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let fade_step = 1f32 / (FADE_TIME * self.sample_rate);
+        self.level = if self.playing {
+            (self.level + fade_step).min(1f32)
+        } else {
+            (self.level - fade_step).max(0f32)
+        };
+
+        let last_phase = self.phase;
+        self.phase = (self.phase + self.settings.pitch / self.sample_rate).fract();
+        let val = match self.settings.waveform {
+            Waveform::Square => if self.phase < 0.5 { 1f32 } else { -1f32 },
+            Waveform::Sine => (self.phase * 2f32 * PI).sin(),
+            Waveform::Triangle => 4f32 * (self.phase - 0.5).abs() - 1f32,
+            Waveform::Noise => {
+                // pick a new random level every half cycle, so the pitch still has an effect
+                if (self.phase * 2f32).floor() != (last_phase * 2f32).floor() {
+                    self.noise ^= self.noise << 13;
+                    self.noise ^= self.noise >> 17;
+                    self.noise ^= self.noise << 5;
+                    self.noise_val = self.noise as f32 / u32::MAX as f32 * 2f32 - 1f32;
+                }
+                self.noise_val
+            }
+        };
+        val * self.settings.volume.clamp(0f32, 1f32) * self.level
+    }
+}
+
 impl Drop for Beep {
     fn drop(&mut self) {
-        self.stop().unwrap();
+        // the audio thread may already be gone, in which case there's nothing to stop
+        let _ = self.stop();
     }
 }
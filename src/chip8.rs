@@ -2,43 +2,59 @@
 
 use rand::RngCore;
 use rand_pcg::Lcg64Xsh32;
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, str::FromStr};
 use crate::hexes::HEXES_FLAT;
+use crate::instruction::{self, Instruction};
+use crate::translator;
 
 pub const MEMORY_SIZE: usize = 4096;
 pub const REGISTER_COUNT: usize = 16;
-const STACK_SIZE: usize = 16;
+// Deepest stack any platform has room for
+pub const MAX_STACK_SIZE: usize = 32;
 
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
 
+// Display contents, one bit per pixel, rows packed left to right.
+pub type FrameBuffer = [u8; SCREEN_WIDTH * SCREEN_HEIGHT / 8];
+
 const REG_V0: usize = 0x00;
 const REG_VF: usize = 0x0F;
 
 const MEMORY_OFFSET: usize = 0x0200;
 
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 2;
+
 pub const INSTRUCTION_SIZE: u16 = 2;
 
 #[derive(Debug)]
 pub struct Chip8 {
-    pub memory: [u8; MEMORY_SIZE],
+    pub memory: [u8; MEMORY_SIZE],  // call memory_changed() after writing to it directly
     pub registers: [u8; REGISTER_COUNT],
-    stack: [u16; STACK_SIZE],
-    pub frame_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT / 8],
-    sp: u8,     // stack pointer
+    stack: [u16; MAX_STACK_SIZE],
+    pub frame_buffer: FrameBuffer,
+    sp: u8,     // stack pointer, the number of addresses on the stack
     pub ir: u16,    // index register
     pub dt: u8,     // delay timer
     st: u8,     // sound timer
     pub pc: u16,    // program counter
     rng: Lcg64Xsh32,
-    pub keypad_waiting: bool,
-    keypad_reg: u8,
-    pub display_changed: bool,
+    pub key_wait: KeyWait,
+    keys_tested_last: u16,  // keys tested by skp/sknp during the previous frame
+    keys_tested_cur: u16,   // keys tested by skp/sknp during the current frame
+    pub display_changed: bool,     // set when the display is drawn to, cleared by whoever shows it
     pub sound_playing: bool,
-    pub quirks_mode: QuirksMode
+    pub frame_count: u64,   // number of frames run since reset
+    pub quirks_mode: QuirksMode,
+    pub platform: Platform,     // which instructions there are
+    pub trace: bool,        // keep a line for every instruction as it runs
+    traced: Vec<String>,    // lines kept since they were last taken
+    decoded: [Option<Instruction>; MEMORY_SIZE],    // instructions decoded so far, by address
+    decoded_platform: Platform  // platform they were decoded for
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ChipError {
     // Operation doesn't exist
     BadOperationError(u8, u8),
@@ -50,6 +66,10 @@ pub enum ChipError {
     ProgramCounterError(u16),
     // Can't read this much into memory
     MemoryOverflowError,
+    // Save state is corrupt or from an incompatible version
+    BadStateError,
+    // Memory access relative to I past the end of memory, at (program counter, address)
+    MemoryAccessError(u16, usize),
 }
 
 impl Error for ChipError {}
@@ -61,29 +81,133 @@ impl fmt::Display for ChipError {
             ChipError::EmptyStackError => "Tried to pop off empty stack".to_string(),
             ChipError::FullStackError => "Tried to push onto full stack".to_string(),
             ChipError::ProgramCounterError(pc) => format!("Program counter out of bounds: 0x{:02X}", pc),
-            ChipError::MemoryOverflowError => format!("Memory overflowed"),
+            ChipError::MemoryOverflowError => "Memory overflowed".to_string(),
+            ChipError::BadStateError => "Save state is invalid".to_string(),
+            ChipError::MemoryAccessError(pc, addr) => format!("Instruction at 0x{:03X} accessed memory out of bounds at 0x{:X}", pc, addr),
         };
         write!(f, "PROCESSOR ERROR: {}", err_str)
     }
 }
 
+// An error from running an instruction, with the state of the machine when it happened.
+#[derive(Debug, Clone)]
+pub struct ChipFault {
+    pub error: ChipError,
+    pub pc: u16,
+    pub opcode: Option<u16>,    // None if the program counter was out of bounds
+    pub registers: [u8; REGISTER_COUNT],
+    pub ir: u16,
+    pub stack: Vec<u16>,
+    pub platform: Platform,     // for disassembling the opcode
+    pub state: Vec<u8>          // save state of the machine, which the failed instruction didn't change
+}
+
+impl ChipFault {
+    // Human readable description of the fault and the machine, for bug reports.
+    pub fn report(&self) -> String {
+        let mut out = format!("{}\n\n", self);
+        for (i, val) in self.registers.iter().enumerate() {
+            out += &format!("V{:X}: 0x{:02X}\n", i, val);
+        }
+        out += &format!("I:  0x{:04X}\nPC: 0x{:04X}\n", self.ir, self.pc);
+        let stack: Vec<String> = self.stack.iter().map(|addr| format!("{:03X}", addr)).collect();
+        out += &format!("Stack: [{}]\n\nMemory:\n", stack.join(", "));
+        // the memory is the first thing in the save state after the header
+        let memory = &self.state[STATE_MAGIC.len() + 1..][..MEMORY_SIZE];
+        for (row, bytes) in memory.chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            out += &format!("{:03X}: {}\n", row * 16, hex.join(" "));
+        }
+        out
+    }
+}
+
+impl Error for ChipFault {}
+
+impl fmt::Display for ChipFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.opcode {
+            Some(opcode) => write!(f, "{} (at 0x{:03X}: {:04X} {})", self.error, self.pc, opcode,
+                translator::translate(opcode, self.platform)),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
 enum ProgramCounterControl {
     Next,
     Skip,
-    Jump(u16)
+    Jump(u16),
+    Wait        // run the same instruction again on the next tick
+}
+
+// Progress of an Fx0A key wait.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyWait {
+    // Not waiting
+    Idle,
+    // Waiting for any key to be pressed
+    Press,
+    // Key was pressed, waiting for it to be released
+    Release(u8)
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct QuirksMode {
     pub shift: bool,
-    pub ldi: bool
+    pub ldi: bool,
+    pub key_press: bool,    // Fx0A finishes on key press instead of release
+    pub stack_depth: usize, // most nested subroutine calls, up to MAX_STACK_SIZE
+    pub memory_wrap: bool   // accesses past the end of memory wrap around instead of failing
 }
 
 impl Default for QuirksMode {
     fn default() -> Self {
-        Self {
-            shift: false,
-            ldi: false
+        Platform::default().quirks()
+    }
+}
+
+// Machines that ran CHIP-8, which disagree on some instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    // The original COSMAC VIP interpreter
+    Vip,
+    // What most modern interpreters do
+    #[default]
+    Modern,
+    // SUPER-CHIP on the HP 48
+    Schip
+}
+
+impl Platform {
+    pub const ALL: [Platform; 3] = [Platform::Vip, Platform::Modern, Platform::Schip];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Vip => "COSMAC VIP",
+            Platform::Modern => "Modern",
+            Platform::Schip => "SUPER-CHIP",
+        }
+    }
+
+    pub fn quirks(&self) -> QuirksMode {
+        match self {
+            Platform::Vip => QuirksMode { shift: true, ldi: true, key_press: false, stack_depth: 12, memory_wrap: true },
+            Platform::Modern => QuirksMode { shift: false, ldi: false, key_press: false, stack_depth: 16, memory_wrap: false },
+            Platform::Schip => QuirksMode { shift: false, ldi: false, key_press: false, stack_depth: MAX_STACK_SIZE, memory_wrap: false },
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "vip" => Ok(Platform::Vip),
+            "modern" => Ok(Platform::Modern),
+            "schip" => Ok(Platform::Schip),
+            _ => Err(format!("Unknown platform '{}', expected vip, modern or schip", s)),
         }
     }
 }
@@ -99,7 +223,7 @@ impl Chip8 {
         Chip8 {
             memory,
             registers: [0; REGISTER_COUNT],
-            stack: [0; STACK_SIZE],
+            stack: [0; MAX_STACK_SIZE],
             frame_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT / 8],
             sp: 0,     // stack pointer
             ir: 0,    // index register
@@ -107,15 +231,23 @@ impl Chip8 {
             st: 0,     // sound timer
             pc: MEMORY_OFFSET as u16,    // program counter
             rng,
-            keypad_waiting: false,
-            keypad_reg: 0,
+            key_wait: KeyWait::Idle,
+            keys_tested_last: 0,
+            keys_tested_cur: 0,
             display_changed: false,
             sound_playing: false,
-            quirks_mode: QuirksMode::default()
+            frame_count: 0,
+            quirks_mode: QuirksMode::default(),
+            platform: Platform::default(),
+            trace: false,
+            traced: Vec::new(),
+            decoded: [None; MEMORY_SIZE],
+            decoded_platform: Platform::default()
         }
     }
 
-    pub fn load(&mut self, bytes: &Vec<u8>) -> Result<(), ChipError> {
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), ChipError> {
+        self.memory_changed();
         for (i, &byte) in bytes.iter().enumerate() {
             let mem_addr = MEMORY_OFFSET + i;
             if mem_addr >= MEMORY_SIZE {
@@ -129,23 +261,39 @@ impl Chip8 {
 
     // Pop from top of stack
     fn stack_pop(&mut self) -> Result<u16, ChipError> {
-        if self.sp == 0xFF {
-            Err(ChipError::EmptyStackError)
-        } else {
-            let val = self.stack[self.sp as usize];
-            self.sp -= 1;
-            Ok(val)
+        if self.sp == 0 {
+            return Err(ChipError::EmptyStackError);
         }
+        self.sp -= 1;
+        Ok(self.stack[self.sp as usize])
     }
 
     // Push to top of stack
     fn stack_push(&mut self, val: u16) -> Result<(), ChipError> {
-        if self.sp == 0xFF {
-            Err(ChipError::FullStackError)
+        let depth = self.quirks_mode.stack_depth.min(MAX_STACK_SIZE);
+        if self.sp as usize >= depth {
+            return Err(ChipError::FullStackError);
+        }
+        self.stack[self.sp as usize] = val;
+        self.sp += 1;
+        Ok(())
+    }
+
+    // Return addresses on the stack, oldest first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    // Address `offset` bytes after I. Past the end of memory this either wraps
+    // around or fails, depending on the platform.
+    fn ir_addr(&self, offset: usize) -> Result<usize, ChipError> {
+        let addr = self.ir as usize + offset;
+        if addr < MEMORY_SIZE {
+            Ok(addr)
+        } else if self.quirks_mode.memory_wrap {
+            Ok(addr % MEMORY_SIZE)
         } else {
-            self.sp += 1;
-            self.stack[self.sp as usize] = val;
-            Ok(())
+            Err(ChipError::MemoryAccessError(self.pc, addr))
         }
     }
 
@@ -154,6 +302,7 @@ impl Chip8 {
         for i in 0..self.frame_buffer.len() {
             self.frame_buffer[i] = 0;
         }
+        self.display_changed = true;
         ProgramCounterControl::Next
     }
 
@@ -325,13 +474,17 @@ impl Chip8 {
     }
 
     // Draw to screen
-    fn op_drw(&mut self, regx: u8, regy: u8, byte_count: u8) -> ProgramCounterControl {
+    fn op_drw(&mut self, regx: u8, regy: u8, byte_count: u8) -> Result<ProgramCounterControl, ChipError> {
         let xpos = self.registers[regx as usize] as usize;
         let ypos = self.registers[regy as usize] as usize;
         let mut overlap = false;
+        // check the whole sprite first, so nothing is drawn if it fails
+        if byte_count > 0 {
+            self.ir_addr(byte_count as usize - 1)?;
+        }
 
         for byte in 0..byte_count {
-            let memory_byte = self.memory[self.ir as usize + byte as usize];
+            let memory_byte = self.memory[self.ir_addr(byte as usize)?];
             let cur_y = (ypos + byte as usize) % SCREEN_HEIGHT;
 
             for bit in 0..8 {
@@ -352,13 +505,14 @@ impl Chip8 {
 
         self.registers[REG_VF] = overlap as u8;
         self.display_changed = true;
-        ProgramCounterControl::Next
+        Ok(ProgramCounterControl::Next)
     }
 
     // skip if key pressed
     fn op_skp(&mut self, reg: u8, key_input: u16) -> ProgramCounterControl {
-        let val = self.registers[reg as usize];
-        if (key_input & (1 << val)) > 0 {
+        let key = self.registers[reg as usize] & 0xF;
+        self.keys_tested_cur |= 1u16 << key;
+        if (key_input & (1 << key)) > 0 {
             ProgramCounterControl::Skip
         } else {
             ProgramCounterControl::Next
@@ -367,8 +521,9 @@ impl Chip8 {
 
     // skip if key not pressed
     fn op_sknp(&mut self, reg: u8, key_input: u16) -> ProgramCounterControl {
-        let val = self.registers[reg as usize];
-        if (key_input & (1u16 << val)) == 0 {
+        let key = self.registers[reg as usize] & 0xF;
+        self.keys_tested_cur |= 1u16 << key;
+        if (key_input & (1u16 << key)) == 0 {
             ProgramCounterControl::Skip
         } else {
             ProgramCounterControl::Next
@@ -381,11 +536,36 @@ impl Chip8 {
         ProgramCounterControl::Next
     }
 
-    // wait until key press
-    fn op_key_wait(&mut self, reg: u8) -> ProgramCounterControl {
-        self.keypad_waiting = true;
-        self.keypad_reg = reg;
-        ProgramCounterControl::Next
+    // wait until a key is pressed and released, then store it in reg.
+    // Like the VIP, the instruction is re-run every tick until the wait is over,
+    // so timers keep counting down in the meantime.
+    fn op_key_wait(&mut self, reg: u8, key_input: u16) -> ProgramCounterControl {
+        match self.key_wait {
+            KeyWait::Idle | KeyWait::Press => match get_lowest_bit_pos(key_input) {
+                Some(key) if self.quirks_mode.key_press => {
+                    self.key_wait = KeyWait::Idle;
+                    self.registers[reg as usize] = key;
+                    ProgramCounterControl::Next
+                },
+                Some(key) => {
+                    self.key_wait = KeyWait::Release(key);
+                    ProgramCounterControl::Wait
+                },
+                None => {
+                    self.key_wait = KeyWait::Press;
+                    ProgramCounterControl::Wait
+                }
+            },
+            KeyWait::Release(key) => {
+                if (key_input & (1 << key)) == 0 {
+                    self.key_wait = KeyWait::Idle;
+                    self.registers[reg as usize] = key;
+                    ProgramCounterControl::Next
+                } else {
+                    ProgramCounterControl::Wait
+                }
+            }
+        }
     }
 
     // load reg into delay timer
@@ -401,7 +581,7 @@ impl Chip8 {
     }
 
     fn op_add_i_vx(&mut self, reg: u8) -> ProgramCounterControl {
-        self.ir += self.registers[reg as usize] as u16;
+        self.ir = self.ir.wrapping_add(self.registers[reg as usize] as u16);
         ProgramCounterControl::Next
     }
 
@@ -411,151 +591,280 @@ impl Chip8 {
         ProgramCounterControl::Next
     }
 
-    fn op_ld_b_vx(&mut self, reg: u8) -> ProgramCounterControl {
+    fn op_ld_b_vx(&mut self, reg: u8) -> Result<ProgramCounterControl, ChipError> {
         let val = self.registers[reg as usize];
-        self.memory[self.ir as usize] = (val / 100) % 10;
-        self.memory[self.ir as usize+1] = (val / 10) % 10;
-        self.memory[self.ir as usize+2] = val % 10;
-        ProgramCounterControl::Next
+        self.ir_addr(2)?;
+        self.store(self.ir_addr(0)?, (val / 100) % 10);
+        self.store(self.ir_addr(1)?, (val / 10) % 10);
+        self.store(self.ir_addr(2)?, val % 10);
+        Ok(ProgramCounterControl::Next)
     }
 
     // Stores registers v0 through vx into memory.
-    fn op_ld_i_vx(&mut self, reg: u8) -> ProgramCounterControl {
+    fn op_ld_i_vx(&mut self, reg: u8) -> Result<ProgramCounterControl, ChipError> {
+        self.ir_addr(reg as usize)?;
         for ind in 0..(reg as usize+1) {
-            self.memory[self.ir as usize + ind] = self.registers[ind];
-        } 
+            self.store(self.ir_addr(ind)?, self.registers[ind]);
+        }
         // weird quirk
         if self.quirks_mode.ldi {
-            self.ir += reg as u16 + 1;
+            self.ir = self.ir.wrapping_add(reg as u16 + 1);
         }
-        ProgramCounterControl::Next
+        Ok(ProgramCounterControl::Next)
     }
 
     // Reads registers v0 through vx from memory.
-    fn op_ld_vx_i(&mut self, reg: u8) -> ProgramCounterControl {
+    fn op_ld_vx_i(&mut self, reg: u8) -> Result<ProgramCounterControl, ChipError> {
+        self.ir_addr(reg as usize)?;
         for ind in 0..(reg as usize+1) {
-            self.registers[ind] = self.memory[self.ir as usize + ind];
+            self.registers[ind] = self.memory[self.ir_addr(ind)?];
         }
         if self.quirks_mode.ldi {
-            self.ir += reg as u16 + 1;
+            self.ir = self.ir.wrapping_add(reg as u16 + 1);
         }
-        ProgramCounterControl::Next
+        Ok(ProgramCounterControl::Next)
     }
 
-    fn run(&mut self, b1: u8, b2: u8, key_input: u16) -> Result<ProgramCounterControl, ChipError> {
-        //println!("{:03x} {}", self.pc, translator::translate(b1, b2));
-        let top_b1 = b1 >> 4;
-        let bottom_b1 = b1 & 0x0F;
-        let top_b2 = b2 >> 4;
-        let bottom_b2 = b2 & 0x0F;
-        match top_b1 {
-            0x0 => match b2 {
-                0xE0 => Ok(self.op_cls()),
-                0xEE => self.op_ret(),
-                _ => Err(ChipError::BadOperationError(b1, b2))
-            }
-            0x1 => Ok(self.op_jp(get_nnn(b1, b2))),
-            0x2 => self.op_call(get_nnn(b1, b2)),
-            0x3 => Ok(self.op_se(bottom_b1, b2)),
-            0x4 => Ok(self.op_sne(bottom_b1, b2)),
-            0x5 => match bottom_b2 {
-                0x0 => Ok(self.op_se_reg(bottom_b1, top_b2)),
-                _ => Err(ChipError::BadOperationError(b1, b2)),
-            },
-            0x6 => Ok(self.op_ld(bottom_b1, b2)),
-            0x7 => Ok(self.op_add(bottom_b1, b2)),
-            0x8 => match bottom_b2 {
-                0x0 => Ok(self.op_ld_reg(bottom_b1, top_b2)),
-                0x1 => Ok(self.op_or(bottom_b1, top_b2)),
-                0x2 => Ok(self.op_and(bottom_b1, top_b2)),
-                0x3 => Ok(self.op_xor(bottom_b1, top_b2)),
-                0x4 => Ok(self.op_add_reg(bottom_b1, top_b2)),
-                0x5 => Ok(self.op_sub_reg(bottom_b1, top_b2)),
-                0x6 => {
-                    if self.quirks_mode.shift {
-                        Ok(self.op_shr_quirky(bottom_b1, top_b2))
-                    } else {
-                        Ok(self.op_shr(bottom_b1))
-                    }
-                },
-                0x7 => Ok(self.op_subn_reg(bottom_b1, top_b2)),
-                0xE => {
-                    if self.quirks_mode.shift {
-                        Ok(self.op_shl_quirky(bottom_b1, top_b2))
-                    } else {
-                        Ok(self.op_shl(bottom_b1))
-                    }
-                },
-                _ => Err(ChipError::BadOperationError(b1, b2))
-            },
-            0x9 => match bottom_b2 {
-                0x0 => Ok(self.op_sne_reg(bottom_b1, top_b2)),
-                _ => Err(ChipError::BadOperationError(b1, b2))
+    fn run(&mut self, instruction: Instruction, key_input: u16) -> Result<ProgramCounterControl, ChipError> {
+        match instruction {
+            Instruction::Cls => Ok(self.op_cls()),
+            Instruction::Ret => self.op_ret(),
+            Instruction::Jp(addr) => Ok(self.op_jp(addr)),
+            Instruction::Call(addr) => self.op_call(addr),
+            Instruction::Se(x, val) => Ok(self.op_se(x, val)),
+            Instruction::Sne(x, val) => Ok(self.op_sne(x, val)),
+            Instruction::SeReg(x, y) => Ok(self.op_se_reg(x, y)),
+            Instruction::Ld(x, val) => Ok(self.op_ld(x, val)),
+            Instruction::Add(x, val) => Ok(self.op_add(x, val)),
+            Instruction::LdReg(x, y) => Ok(self.op_ld_reg(x, y)),
+            Instruction::Or(x, y) => Ok(self.op_or(x, y)),
+            Instruction::And(x, y) => Ok(self.op_and(x, y)),
+            Instruction::Xor(x, y) => Ok(self.op_xor(x, y)),
+            Instruction::AddReg(x, y) => Ok(self.op_add_reg(x, y)),
+            Instruction::SubReg(x, y) => Ok(self.op_sub_reg(x, y)),
+            Instruction::Shr(x, y) => {
+                if self.quirks_mode.shift {
+                    Ok(self.op_shr_quirky(x, y))
+                } else {
+                    Ok(self.op_shr(x))
+                }
             },
-            0xA => Ok(self.op_ld_i(get_nnn(b1, b2))),
-            0xB => Ok(self.op_jp_v0(get_nnn(b1, b2))),
-            0xC => Ok(self.op_rnd(bottom_b1, b2)),
-            0xD => Ok(self.op_drw(bottom_b1, top_b2, bottom_b2)),
-            0xE => match b2 {
-                0x9E => Ok(self.op_skp(bottom_b1, key_input)),
-                0xA1 => Ok(self.op_sknp(bottom_b1, key_input)),
-                _ => Err(ChipError::BadOperationError(b1, b2)),
+            Instruction::SubnReg(x, y) => Ok(self.op_subn_reg(x, y)),
+            Instruction::Shl(x, y) => {
+                if self.quirks_mode.shift {
+                    Ok(self.op_shl_quirky(x, y))
+                } else {
+                    Ok(self.op_shl(x))
+                }
             },
-            0xF => match b2 {
-                0x07 => Ok(self.op_ld_vx_dt(bottom_b1)),
-                0x0A => Ok(self.op_key_wait(bottom_b1)),
-                0x15 => Ok(self.op_ld_dt_vx(bottom_b1)),
-                0x18 => Ok(self.op_ld_st_vx(bottom_b1)),
-                0x1E => Ok(self.op_add_i_vx(bottom_b1)),
-                0x29 => Ok(self.op_ld_f_vx(bottom_b1)),
-                0x33 => Ok(self.op_ld_b_vx(bottom_b1)),
-                0x55 => Ok(self.op_ld_i_vx(bottom_b1)),
-                0x65 => Ok(self.op_ld_vx_i(bottom_b1)),
-                _ => Err(ChipError::BadOperationError(b1, b2))
-            }
-            _ => Err(ChipError::BadOperationError(b1, b2))
+            Instruction::SneReg(x, y) => Ok(self.op_sne_reg(x, y)),
+            Instruction::LdI(addr) => Ok(self.op_ld_i(addr)),
+            Instruction::JpV0(addr) => Ok(self.op_jp_v0(addr)),
+            Instruction::Rnd(x, mask) => Ok(self.op_rnd(x, mask)),
+            Instruction::Drw(x, y, rows) => self.op_drw(x, y, rows),
+            Instruction::Skp(x) => Ok(self.op_skp(x, key_input)),
+            Instruction::Sknp(x) => Ok(self.op_sknp(x, key_input)),
+            Instruction::LdVxDt(x) => Ok(self.op_ld_vx_dt(x)),
+            Instruction::KeyWait(x) => Ok(self.op_key_wait(x, key_input)),
+            Instruction::LdDtVx(x) => Ok(self.op_ld_dt_vx(x)),
+            Instruction::LdStVx(x) => Ok(self.op_ld_st_vx(x)),
+            Instruction::AddIVx(x) => Ok(self.op_add_i_vx(x)),
+            Instruction::LdFVx(x) => Ok(self.op_ld_f_vx(x)),
+            Instruction::LdBVx(x) => self.op_ld_b_vx(x),
+            Instruction::LdIVx(x) => self.op_ld_i_vx(x),
+            Instruction::LdVxI(x) => self.op_ld_vx_i(x)
         }
     }
 
-    pub fn tick(&mut self, key_input: u16) -> Result<(), ChipError> {
-        if self.keypad_waiting {
-            return Ok(());
-        }
+    // Trace lines for the instructions run since this was last called, for whoever
+    // is running the machine to show wherever suits the frontend.
+    pub fn take_trace(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.traced)
+    }
+
+    pub fn tick(&mut self, key_input: u16) -> Result<(), ChipFault> {
         if self.pc as usize > MEMORY_SIZE-INSTRUCTION_SIZE as usize {
-            return Err(ChipError::ProgramCounterError(self.pc));
+            return Err(self.fault(ChipError::ProgramCounterError(self.pc), None));
+        }
+        let pc = self.pc as usize;
+        // a key wait runs over and over, so only trace it the first time
+        if self.trace && self.key_wait == KeyWait::Idle {
+            self.traced.push(format!("{:03x} {}", self.pc, translator::translate(self.opcode_at(pc), self.platform)));
         }
-        let instruction = (self.memory[self.pc as usize], self.memory[self.pc as usize+1]);
 
-        let res = self.run(instruction.0, instruction.1, key_input)?;
+        let instruction = match self.decoded[pc] {
+            Some(instruction) if self.decoded_platform == self.platform => instruction,
+            _ => self.decode(pc)?
+        };
+        let res = self.run(instruction, key_input)
+            .map_err(|error| self.fault(error, Some(self.opcode_at(pc))))?;
         match res {
             ProgramCounterControl::Next => self.pc += INSTRUCTION_SIZE,
             ProgramCounterControl::Skip => self.pc += 2*INSTRUCTION_SIZE,
             ProgramCounterControl::Jump(addr) => self.pc = addr,
+            ProgramCounterControl::Wait => (),
         };
 
         Ok(())
     }
 
+    fn opcode_at(&self, addr: usize) -> u16 {
+        u16::from_be_bytes([self.memory[addr], self.memory[addr + 1]])
+    }
+
+    // Decodes the instruction at `addr` and remembers it, so running it again
+    // doesn't need decoding until the memory there is written to.
+    fn decode(&mut self, addr: usize) -> Result<Instruction, ChipFault> {
+        if self.decoded_platform != self.platform {
+            self.memory_changed();
+        }
+        let opcode = self.opcode_at(addr);
+        let instruction = instruction::decode(opcode, self.platform)
+            .ok_or_else(|| self.fault(ChipError::BadOperationError((opcode >> 8) as u8, opcode as u8), Some(opcode)))?;
+        self.decoded[addr] = Some(instruction);
+        Ok(instruction)
+    }
+
+    // Writes a byte of memory, forgetting the instructions it was part of.
+    fn store(&mut self, addr: usize, val: u8) {
+        self.memory[addr] = val;
+        self.decoded[addr] = None;
+        if addr > 0 {
+            self.decoded[addr - 1] = None;
+        }
+    }
+
+    // Forgets every decoded instruction, for after memory has been written
+    // to from outside.
+    pub fn memory_changed(&mut self) {
+        self.decoded = [None; MEMORY_SIZE];
+        self.decoded_platform = self.platform;
+    }
+
     // Runs after 1/60 sec has elapsed and timers should be ticked down.
     pub fn frame(&mut self) {
-        self.display_changed = false;
+        self.frame_count += 1;
+        self.keys_tested_last = self.keys_tested_cur;
+        self.keys_tested_cur = 0;
+        // the sound plays for as many frames as the sound timer was set to
+        self.sound_playing = self.st > 0;
         if self.dt > 0 {
             self.dt -= 1;
         }
         if self.st > 0 {
             self.st -= 1;
         }
-        self.sound_playing = self.st > 0;
     }
 
-    pub fn keypad_press(&mut self, key_press: u16) {
-        if self.keypad_waiting {
-            if let Some(bit) = get_lowest_bit_pos(key_press) {
-                self.keypad_waiting = false;
+    fn fault(&self, error: ChipError, opcode: Option<u16>) -> ChipFault {
+        ChipFault {
+            error,
+            pc: self.pc,
+            opcode,
+            registers: self.registers,
+            ir: self.ir,
+            stack: self.stack().to_vec(),
+            platform: self.platform,
+            state: self.save_state()
+        }
+    }
 
-                self.registers[self.keypad_reg as usize] = bit as u8;
-            }
+    // Saves the machine state, apart from settings and the random number generator.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_VERSION);
+        out.extend_from_slice(&self.memory);
+        out.extend_from_slice(&self.registers);
+        for val in self.stack {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+        out.push(self.sp);
+        out.extend_from_slice(&self.ir.to_le_bytes());
+        out.push(self.dt);
+        out.push(self.st);
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.frame_buffer);
+        match self.key_wait {
+            KeyWait::Idle => out.extend_from_slice(&[0, 0]),
+            KeyWait::Press => out.extend_from_slice(&[1, 0]),
+            KeyWait::Release(key) => out.extend_from_slice(&[2, key]),
         }
+        out.extend_from_slice(&self.frame_count.to_le_bytes());
+        out
+    }
+
+    // Restores a state written by save_state. Nothing is changed if it's invalid.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), ChipError> {
+        let mut reader = StateReader { bytes };
+        if reader.take(4)? != STATE_MAGIC || reader.take(1)?[0] != STATE_VERSION {
+            return Err(ChipError::BadStateError);
+        }
+        let mut state = Chip8 { rng: self.rng.clone(), traced: Vec::new(), ..*self };
+        state.memory.copy_from_slice(reader.take(MEMORY_SIZE)?);
+        state.memory_changed();
+        state.registers.copy_from_slice(reader.take(REGISTER_COUNT)?);
+        for val in state.stack.iter_mut() {
+            *val = reader.u16()?;
+        }
+        state.sp = reader.u8()?;
+        if state.sp as usize > MAX_STACK_SIZE {
+            return Err(ChipError::BadStateError);
+        }
+        state.ir = reader.u16()?;
+        state.dt = reader.u8()?;
+        state.st = reader.u8()?;
+        state.pc = reader.u16()?;
+        let frame_buffer_len = state.frame_buffer.len();
+        state.frame_buffer.copy_from_slice(reader.take(frame_buffer_len)?);
+        state.key_wait = match (reader.u8()?, reader.u8()?) {
+            (0, _) => KeyWait::Idle,
+            (1, _) => KeyWait::Press,
+            (2, key) if key < 16 => KeyWait::Release(key),
+            _ => return Err(ChipError::BadStateError),
+        };
+        state.frame_count = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        if !reader.bytes.is_empty() {
+            return Err(ChipError::BadStateError);
+        }
+        state.display_changed = true;
+        *self = state;
+        Ok(())
+    }
+
+    // Keys the program has tested for recently, as a bitmask.
+    // While waiting for a key press, every key counts as tested.
+    pub fn keys_tested(&self) -> u16 {
+        match self.key_wait {
+            KeyWait::Idle => self.keys_tested_last | self.keys_tested_cur,
+            KeyWait::Press => 0xFFFF,
+            KeyWait::Release(key) => 1 << key
+        }
+    }
+}
+
+// Reads a save state piece by piece.
+struct StateReader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ChipError> {
+        if self.bytes.len() < len {
+            return Err(ChipError::BadStateError);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ChipError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ChipError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
     }
 }
 
@@ -566,6 +875,12 @@ pub fn get_nnn(b1: u8, b2: u8) -> u16 {
     (x1 * 0x0100 + x2) & 0x0FFF
 }
 
+// Whether the pixel at (x, y) is lit.
+pub fn get_pixel(frame_buffer: &FrameBuffer, x: usize, y: usize) -> bool {
+    let byte = frame_buffer[(y * SCREEN_WIDTH + x) / 8];
+    (byte & (1 << (7 - x % 8))) > 0
+}
+
 // Get lowest set bit index
 fn get_lowest_bit_pos(num: u16) -> Option<u8> {
     for i in 0..16 {
@@ -575,3 +890,591 @@ fn get_lowest_bit_pos(num: u16) -> Option<u8> {
     }
     return None;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_pcg::Pcg32;
+
+    fn machine(program: &[u8], platform: Platform) -> Chip8 {
+        let mut chip8 = Chip8::new(Pcg32::new(0, 0));
+        chip8.quirks_mode = platform.quirks();
+        chip8.platform = platform;
+        chip8.load(program).unwrap();
+        chip8
+    }
+
+    // Runs one instruction at the program counter, with the given keys held.
+    fn exec_keys(chip8: &mut Chip8, opcode: u16, keys: u16) {
+        let pc = chip8.pc as usize;
+        chip8.memory[pc..pc + 2].copy_from_slice(&opcode.to_be_bytes());
+        chip8.memory_changed();
+        chip8.tick(keys).unwrap();
+    }
+
+    fn exec(chip8: &mut Chip8, opcode: u16) {
+        exec_keys(chip8, opcode, 0);
+    }
+
+    // Every combination of the boolean quirks.
+    fn all_quirks() -> Vec<QuirksMode> {
+        let mut all = vec![];
+        for bits in 0..8 {
+            all.push(QuirksMode {
+                shift: bits & 1 > 0,
+                ldi: bits & 2 > 0,
+                key_press: bits & 4 > 0,
+                ..QuirksMode::default()
+            });
+        }
+        all
+    }
+
+    fn pixel(chip8: &Chip8, x: usize, y: usize) -> bool {
+        get_pixel(&chip8.frame_buffer, x, y)
+    }
+
+    #[test]
+    fn call_and_return() {
+        // 200: call 206, 202: jump 202, 206: return
+        let mut chip8 = machine(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x00, 0xEE], Platform::Modern);
+        chip8.tick(0).unwrap();
+        assert_eq!(chip8.pc, 0x206);
+        assert_eq!(chip8.stack(), &[0x202]);
+        chip8.tick(0).unwrap();
+        assert_eq!(chip8.pc, 0x202);
+        assert!(chip8.stack().is_empty());
+    }
+
+    #[test]
+    fn nested_calls_return_in_order() {
+        // 200: call 204, 202: jump 202, 204: call 208, 206: return, 208: return
+        let mut chip8 = machine(&[0x22, 0x04, 0x12, 0x02, 0x22, 0x08, 0x00, 0xEE, 0x00, 0xEE], Platform::Modern);
+        let expected: [(u16, &[u16]); 4] = [
+            (0x204, &[0x202]),
+            (0x208, &[0x202, 0x206]),
+            (0x206, &[0x202]),
+            (0x202, &[]),
+        ];
+        for (pc, stack) in expected {
+            chip8.tick(0).unwrap();
+            assert_eq!(chip8.pc, pc);
+            assert_eq!(chip8.stack(), stack);
+        }
+    }
+
+    #[test]
+    fn stack_depth_per_platform() {
+        for (platform, depth) in [(Platform::Vip, 12), (Platform::Modern, 16), (Platform::Schip, MAX_STACK_SIZE)] {
+            // 200: call 200, forever
+            let mut chip8 = machine(&[0x22, 0x00], platform);
+            for _ in 0..depth {
+                chip8.tick(0).unwrap();
+            }
+            assert_eq!(chip8.stack().len(), depth, "{}", platform.name());
+            assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::FullStackError, .. })), "{}", platform.name());
+            assert_eq!(chip8.stack().len(), depth);
+            assert_eq!(chip8.pc, 0x200);
+        }
+    }
+
+    #[test]
+    fn return_with_empty_stack() {
+        let mut chip8 = machine(&[0x00, 0xEE], Platform::Modern);
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::EmptyStackError, .. })));
+        assert_eq!(chip8.pc, 0x200);
+        assert!(chip8.stack().is_empty());
+    }
+
+    #[test]
+    fn full_stack_unwinds() {
+        // 200: call 200, 202: return
+        let mut chip8 = machine(&[0x22, 0x00, 0x00, 0xEE], Platform::Vip);
+        while chip8.tick(0).is_ok() {}
+        assert_eq!(chip8.stack().len(), 12);
+        chip8.pc = 0x202;
+        for _ in 0..12 {
+            chip8.tick(0).unwrap();
+            assert_eq!(chip8.pc, 0x202);
+        }
+        assert!(chip8.stack().is_empty());
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::EmptyStackError, .. })));
+    }
+
+    #[test]
+    fn lowering_depth_keeps_existing_calls() {
+        let mut chip8 = machine(&[0x22, 0x00], Platform::Modern);
+        for _ in 0..4 {
+            chip8.tick(0).unwrap();
+        }
+        chip8.quirks_mode.stack_depth = 2;
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::FullStackError, .. })));
+        assert_eq!(chip8.stack(), &[0x202; 4]);
+    }
+
+    #[test]
+    fn save_state_keeps_stack() {
+        let mut chip8 = machine(&[0x22, 0x00], Platform::Modern);
+        for _ in 0..3 {
+            chip8.tick(0).unwrap();
+        }
+        let state = chip8.save_state();
+        let mut restored = machine(&[], Platform::Modern);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.stack(), chip8.stack());
+    }
+
+    #[test]
+    fn memory_access_past_end_fails() {
+        // I = FFF, store V0-V1
+        let mut chip8 = machine(&[0xAF, 0xFF, 0xF1, 0x55], Platform::Modern);
+        chip8.registers[0] = 1;
+        chip8.registers[1] = 2;
+        chip8.tick(0).unwrap();
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::MemoryAccessError(0x202, 0x1000), .. })));
+        assert_eq!(chip8.memory[0xFFF], 0);
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn memory_access_past_end_wraps() {
+        // I = FFF, store V0-V1
+        let mut chip8 = machine(&[0xAF, 0xFF, 0xF1, 0x55], Platform::Vip);
+        chip8.registers[0] = 1;
+        chip8.registers[1] = 2;
+        chip8.tick(0).unwrap();
+        chip8.tick(0).unwrap();
+        assert_eq!(chip8.memory[0xFFF], 1);
+        assert_eq!(chip8.memory[0x000], 2);
+        assert_eq!(chip8.ir, 0x1001);
+    }
+
+    #[test]
+    fn add_to_i_doesnt_overflow() {
+        // I += V0
+        let mut chip8 = machine(&[0xF0, 0x1E], Platform::Modern);
+        chip8.registers[0] = 0xFF;
+        chip8.ir = 0xFFF0;
+        chip8.tick(0).unwrap();
+        assert_eq!(chip8.ir, 0x00EF);
+    }
+
+    #[test]
+    fn draw_past_end_draws_nothing() {
+        // I = FFE, draw 4 rows at V0, V0
+        let mut chip8 = machine(&[0xAF, 0xFE, 0xD0, 0x04], Platform::Modern);
+        chip8.memory[0xFFE] = 0xFF;
+        chip8.tick(0).unwrap();
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::MemoryAccessError(0x202, 0x1001), .. })));
+        assert!(chip8.frame_buffer.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn fault_has_context() {
+        // 200: V3 = 7, 202: bad opcode
+        let mut chip8 = machine(&[0x63, 0x07, 0x01, 0x23], Platform::Modern);
+        chip8.tick(0).unwrap();
+        let fault = chip8.tick(0).unwrap_err();
+        assert!(matches!(fault.error, ChipError::BadOperationError(0x01, 0x23)));
+        assert_eq!(fault.pc, 0x202);
+        assert_eq!(fault.opcode, Some(0x0123));
+        assert_eq!(fault.registers[3], 7);
+        let mut restored = machine(&[], Platform::Modern);
+        restored.load_state(&fault.state).unwrap();
+        assert_eq!(restored.pc, 0x202);
+        assert_eq!(restored.registers[3], 7);
+    }
+
+    #[test]
+    fn op_00e0_clears_display() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.frame_buffer = [0xFF; SCREEN_WIDTH * SCREEN_HEIGHT / 8];
+        exec(&mut chip8, 0x00E0);
+        assert!(chip8.frame_buffer.iter().all(|&byte| byte == 0));
+        assert!(chip8.display_changed);
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn op_0nnn_is_rejected() {
+        let mut chip8 = machine(&[0x01, 0x23], Platform::Modern);
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::BadOperationError(0x01, 0x23), .. })));
+        // only 00E0 and 00EE are real, not every 0nE0 and 0nEE
+        let mut chip8 = machine(&[0x07, 0xE0], Platform::Modern);
+        assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::BadOperationError(0x07, 0xE0), .. })));
+    }
+
+    #[test]
+    fn op_1nnn_jumps() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        exec(&mut chip8, 0x1ABC);
+        assert_eq!(chip8.pc, 0xABC);
+    }
+
+    #[test]
+    fn op_3xkk_4xkk_skip_on_immediate() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.registers[5] = 0x42;
+        for (opcode, pc) in [(0x3542, 0x204), (0x3543, 0x202), (0x4542, 0x202), (0x4543, 0x204)] {
+            chip8.pc = 0x200;
+            exec(&mut chip8, opcode);
+            assert_eq!(chip8.pc, pc, "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn op_5xy0_9xy0_skip_on_registers() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.registers[1] = 7;
+        chip8.registers[2] = 7;
+        chip8.registers[3] = 8;
+        for (opcode, pc) in [(0x5120, 0x204), (0x5130, 0x202), (0x9120, 0x202), (0x9130, 0x204)] {
+            chip8.pc = 0x200;
+            exec(&mut chip8, opcode);
+            assert_eq!(chip8.pc, pc, "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn op_5xyn_9xyn_with_low_nibble_are_rejected() {
+        for opcode in [0x5121u16, 0x9121] {
+            let mut chip8 = machine(&opcode.to_be_bytes(), Platform::Modern);
+            assert!(matches!(chip8.tick(0), Err(ChipFault { error: ChipError::BadOperationError(_, _), .. })));
+        }
+    }
+
+    #[test]
+    fn op_6xkk_7xkk_load_and_add() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        exec(&mut chip8, 0x6AF0);
+        assert_eq!(chip8.registers[0xA], 0xF0);
+        exec(&mut chip8, 0x7A20);
+        // wraps around without touching VF
+        assert_eq!(chip8.registers[0xA], 0x10);
+        assert_eq!(chip8.registers[0xF], 0);
+    }
+
+    #[test]
+    fn op_8xy0_to_8xy3_logic() {
+        for (opcode, expected) in [(0x8120, 0b1010), (0x8121, 0b1110), (0x8122, 0b1000), (0x8123, 0b0110)] {
+            let mut chip8 = machine(&[], Platform::Modern);
+            chip8.registers[1] = 0b1100;
+            chip8.registers[2] = 0b1010;
+            exec(&mut chip8, opcode);
+            assert_eq!(chip8.registers[1], expected, "{:04x}", opcode);
+            assert_eq!(chip8.registers[2], 0b1010);
+        }
+    }
+
+    #[test]
+    fn op_8xy4_add_sets_carry() {
+        for quirks in all_quirks() {
+            for (a, b, sum, carry) in [(0x10, 0x20, 0x30, 0), (0xFF, 0x01, 0x00, 1), (0xF0, 0xF0, 0xE0, 1)] {
+                let mut chip8 = machine(&[], Platform::Modern);
+                chip8.quirks_mode = quirks;
+                chip8.registers[1] = a;
+                chip8.registers[2] = b;
+                exec(&mut chip8, 0x8124);
+                assert_eq!((chip8.registers[1], chip8.registers[0xF]), (sum, carry), "{:?} {} {}", quirks, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn op_8xy5_8xy7_subtract_sets_not_borrow() {
+        for quirks in all_quirks() {
+            for (a, b) in [(0x30u8, 0x10u8), (0x10, 0x30), (0x20, 0x20)] {
+                let mut chip8 = machine(&[], Platform::Modern);
+                chip8.quirks_mode = quirks;
+                chip8.registers[1] = a;
+                chip8.registers[2] = b;
+                exec(&mut chip8, 0x8125);
+                assert_eq!((chip8.registers[1], chip8.registers[0xF]), (a.wrapping_sub(b), (a >= b) as u8), "{:?} {} {}", quirks, a, b);
+
+                chip8.registers[1] = a;
+                exec(&mut chip8, 0x8127);
+                assert_eq!((chip8.registers[1], chip8.registers[0xF]), (b.wrapping_sub(a), (b >= a) as u8), "{:?} {} {}", quirks, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn op_8xy6_8xye_shift_follows_quirk() {
+        for quirks in all_quirks() {
+            let mut chip8 = machine(&[], Platform::Modern);
+            chip8.quirks_mode = quirks;
+            chip8.registers[1] = 0b1000_0001;
+            chip8.registers[2] = 0b0100_0010;
+            exec(&mut chip8, 0x8126);
+            // the quirk shifts Vy into Vx, otherwise Vx is shifted in place
+            let expected = if quirks.shift { (0b0010_0001, 0) } else { (0b0100_0000, 1) };
+            assert_eq!((chip8.registers[1], chip8.registers[0xF]), expected, "{:?}", quirks);
+
+            chip8.registers[1] = 0b1000_0001;
+            exec(&mut chip8, 0x812E);
+            let expected = if quirks.shift { (0b1000_0100, 0) } else { (0b0000_0010, 1) };
+            assert_eq!((chip8.registers[1], chip8.registers[0xF]), expected, "{:?}", quirks);
+            assert_eq!(chip8.registers[2], 0b0100_0010);
+        }
+    }
+
+    #[test]
+    fn op_8xyn_flag_wins_when_vf_is_the_target() {
+        for (opcode, a, b, flag) in [(0x8F14, 0xFFu8, 0x01u8, 1), (0x8F15, 0x01, 0x02, 0), (0x8F17, 0x02, 0x01, 0)] {
+            let mut chip8 = machine(&[], Platform::Modern);
+            chip8.registers[0xF] = a;
+            chip8.registers[1] = b;
+            exec(&mut chip8, opcode);
+            assert_eq!(chip8.registers[0xF], flag, "{:04x}", opcode);
+        }
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.registers[0xF] = 0b1000_0000;
+        exec(&mut chip8, 0x8F0E);
+        assert_eq!(chip8.registers[0xF], 1);
+    }
+
+    #[test]
+    fn op_8xyn_unknown_is_rejected() {
+        for low in [0x8u16, 0x9, 0xA, 0xB, 0xC, 0xD, 0xF] {
+            let opcode = 0x8120 | low;
+            let mut chip8 = machine(&opcode.to_be_bytes(), Platform::Modern);
+            assert!(chip8.tick(0).is_err(), "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn op_annn_bnnn() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        exec(&mut chip8, 0xA123);
+        assert_eq!(chip8.ir, 0x123);
+        chip8.registers[0] = 0x10;
+        exec(&mut chip8, 0xB300);
+        assert_eq!(chip8.pc, 0x310);
+    }
+
+    #[test]
+    fn op_cxkk_masks_random_value() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        for _ in 0..32 {
+            chip8.pc = 0x200;
+            exec(&mut chip8, 0xC30F);
+            assert_eq!(chip8.registers[3] & 0xF0, 0);
+        }
+        exec(&mut chip8, 0xC300);
+        assert_eq!(chip8.registers[3], 0);
+    }
+
+    #[test]
+    fn op_dxyn_draws_and_detects_collision() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.ir = 0x300;
+        chip8.memory[0x300] = 0b1100_0000;
+        chip8.memory[0x301] = 0b0100_0000;
+        chip8.registers[0] = 10;
+        chip8.registers[1] = 5;
+        exec(&mut chip8, 0xD012);
+        assert!(pixel(&chip8, 10, 5) && pixel(&chip8, 11, 5) && !pixel(&chip8, 10, 6) && pixel(&chip8, 11, 6));
+        assert_eq!(chip8.registers[0xF], 0);
+
+        // drawing the same sprite again erases it and reports the collision
+        exec(&mut chip8, 0xD012);
+        assert!(chip8.frame_buffer.iter().all(|&byte| byte == 0));
+        assert_eq!(chip8.registers[0xF], 1);
+    }
+
+    #[test]
+    fn op_dxyn_wraps_around_edges() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.ir = 0x300;
+        chip8.memory[0x300] = 0b1100_0000;
+        chip8.memory[0x301] = 0b1100_0000;
+        chip8.registers[0] = SCREEN_WIDTH as u8 - 1;
+        chip8.registers[1] = SCREEN_HEIGHT as u8 - 1;
+        exec(&mut chip8, 0xD012);
+        assert!(pixel(&chip8, SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+        assert!(pixel(&chip8, 0, SCREEN_HEIGHT - 1));
+        assert!(pixel(&chip8, SCREEN_WIDTH - 1, 0));
+        assert!(pixel(&chip8, 0, 0));
+
+        // coordinates past the edge start wrapped too
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.ir = 0x300;
+        chip8.memory[0x300] = 0b1000_0000;
+        chip8.registers[0] = SCREEN_WIDTH as u8 + 3;
+        chip8.registers[1] = SCREEN_HEIGHT as u8 + 2;
+        exec(&mut chip8, 0xD011);
+        assert!(pixel(&chip8, 3, 2));
+    }
+
+    #[test]
+    fn op_dxy0_draws_nothing() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        exec(&mut chip8, 0xD010);
+        assert!(chip8.frame_buffer.iter().all(|&byte| byte == 0));
+        assert_eq!(chip8.registers[0xF], 0);
+    }
+
+    #[test]
+    fn op_ex9e_exa1_skip_on_keys() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.registers[4] = 0xB;
+        for (opcode, keys, pc) in [(0xE49E, 1 << 0xB, 0x204), (0xE49E, 1 << 0xA, 0x202), (0xE4A1, 1 << 0xB, 0x202), (0xE4A1, 0, 0x204)] {
+            chip8.pc = 0x200;
+            exec_keys(&mut chip8, opcode, keys);
+            assert_eq!(chip8.pc, pc, "{:04x} {:04x}", opcode, keys);
+        }
+        assert_eq!(chip8.keys_tested(), 1 << 0xB);
+    }
+
+    #[test]
+    fn op_fx0a_waits_for_release() {
+        let mut chip8 = machine(&[0xF3, 0x0A], Platform::Modern);
+        chip8.tick(0).unwrap();
+        assert_eq!((chip8.pc, chip8.key_wait), (0x200, KeyWait::Press));
+        chip8.tick((1 << 6) | (1 << 9)).unwrap();
+        assert_eq!((chip8.pc, chip8.key_wait), (0x200, KeyWait::Release(6)));
+        chip8.tick(1 << 9).unwrap();
+        assert_eq!((chip8.pc, chip8.registers[3], chip8.key_wait), (0x202, 6, KeyWait::Idle));
+    }
+
+    #[test]
+    fn op_fx0a_key_press_quirk() {
+        let mut chip8 = machine(&[0xF3, 0x0A], Platform::Modern);
+        chip8.quirks_mode.key_press = true;
+        chip8.tick(0).unwrap();
+        chip8.tick(1 << 0xC).unwrap();
+        assert_eq!((chip8.pc, chip8.registers[3], chip8.key_wait), (0x202, 0xC, KeyWait::Idle));
+    }
+
+    #[test]
+    fn op_fx07_fx15_fx18_timers() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.registers[1] = 3;
+        exec(&mut chip8, 0xF115);
+        exec(&mut chip8, 0xF118);
+        chip8.frame();
+        assert!(chip8.sound_playing);
+        exec(&mut chip8, 0xF207);
+        assert_eq!(chip8.registers[2], 2);
+        chip8.frame();
+        chip8.frame();
+        chip8.frame();
+        assert!(!chip8.sound_playing);
+        exec(&mut chip8, 0xF207);
+        assert_eq!(chip8.registers[2], 0);
+    }
+
+    #[test]
+    fn op_fx1e_adds_to_i() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.ir = 0x100;
+        chip8.registers[2] = 0x22;
+        exec(&mut chip8, 0xF21E);
+        assert_eq!(chip8.ir, 0x122);
+        assert_eq!(chip8.registers[0xF], 0);
+    }
+
+    #[test]
+    fn op_fx29_points_at_font() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        for digit in 0..16u8 {
+            chip8.registers[7] = digit | 0x30;
+            exec(&mut chip8, 0xF729);
+            assert_eq!(chip8.ir, digit as u16 * 5);
+            assert_eq!(&chip8.memory[chip8.ir as usize..][..5], &HEXES_FLAT[digit as usize * 5..][..5]);
+        }
+    }
+
+    #[test]
+    fn op_fx33_stores_bcd() {
+        for (val, digits) in [(0u8, [0, 0, 0]), (7, [0, 0, 7]), (42, [0, 4, 2]), (255, [2, 5, 5]), (100, [1, 0, 0])] {
+            let mut chip8 = machine(&[], Platform::Modern);
+            chip8.ir = 0x300;
+            chip8.registers[9] = val;
+            exec(&mut chip8, 0xF933);
+            assert_eq!(&chip8.memory[0x300..0x303], &digits, "{}", val);
+            assert_eq!(chip8.ir, 0x300);
+        }
+    }
+
+    #[test]
+    fn op_fx55_fx65_store_and_load() {
+        for quirks in all_quirks() {
+            let mut chip8 = machine(&[], Platform::Modern);
+            chip8.quirks_mode = quirks;
+            chip8.ir = 0x300;
+            for i in 0..REGISTER_COUNT {
+                chip8.registers[i] = i as u8 + 1;
+            }
+            exec(&mut chip8, 0xF355);
+            assert_eq!(&chip8.memory[0x300..0x305], &[1, 2, 3, 4, 0], "{:?}", quirks);
+            // the load/store quirk moves I past the registers
+            assert_eq!(chip8.ir, if quirks.ldi { 0x304 } else { 0x300 }, "{:?}", quirks);
+
+            chip8.registers = [0; REGISTER_COUNT];
+            chip8.ir = 0x300;
+            exec(&mut chip8, 0xF265);
+            assert_eq!(&chip8.registers[..4], &[1, 2, 3, 0], "{:?}", quirks);
+            assert_eq!(chip8.ir, if quirks.ldi { 0x303 } else { 0x300 }, "{:?}", quirks);
+        }
+    }
+
+    #[test]
+    fn op_exkk_fxkk_unknown_are_rejected() {
+        for opcode in [0xE100u16, 0xE19F, 0xF100, 0xF166, 0xF1FF] {
+            let mut chip8 = machine(&opcode.to_be_bytes(), Platform::Modern);
+            assert!(chip8.tick(0).is_err(), "{:04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn program_counter_past_memory_fails() {
+        let mut chip8 = machine(&[], Platform::Modern);
+        chip8.pc = MEMORY_SIZE as u16 - 1;
+        let fault = chip8.tick(0).unwrap_err();
+        assert!(matches!(fault.error, ChipError::ProgramCounterError(0xFFF)));
+        assert_eq!(fault.opcode, None);
+    }
+
+    #[test]
+    fn overwritten_instruction_runs_new_code() {
+        let mut chip8 = machine(&[
+            0x60, 0x62,     // V0 = 0x62
+            0x61, 0x77,     // V1 = 0x77
+            0x22, 0x10,     // call 0x210, decoding it
+            0xA2, 0x10,     // I = 0x210
+            0xF1, 0x55,     // overwrite 0x210 with 6277
+            0x22, 0x10,     // call it again
+            0x12, 0x0C,
+            0x00, 0x00,
+            0x62, 0x55,     // 0x210: V2 = 0x55
+            0x00, 0xEE,
+        ], Platform::Modern);
+        for _ in 0..5 {
+            chip8.tick(0).unwrap();
+        }
+        assert_eq!(chip8.registers[2], 0x55);
+        for _ in 0..5 {
+            chip8.tick(0).unwrap();
+        }
+        assert_eq!(chip8.registers[2], 0x77);
+        assert_eq!(chip8.pc, 0x20C);
+    }
+
+    #[test]
+    fn nnn_from_bytes() {
+        assert_eq!(get_nnn(0x1A, 0xBC), 0xABC);
+        assert_eq!(get_nnn(0xFF, 0xFF), 0xFFF);
+        assert_eq!(get_nnn(0x00, 0x00), 0x000);
+        assert_eq!(get_nnn(0x20, 0x01), 0x001);
+    }
+
+    #[test]
+    fn lowest_bit_pos() {
+        assert_eq!(get_lowest_bit_pos(0), None);
+        assert_eq!(get_lowest_bit_pos(1), Some(0));
+        assert_eq!(get_lowest_bit_pos(0b1010_0000), Some(5));
+        assert_eq!(get_lowest_bit_pos(0x8000), Some(15));
+        assert_eq!(get_lowest_bit_pos(0xFFFF), Some(0));
+    }
+}
@@ -0,0 +1,182 @@
+// Command line options.
+
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, builder::BoolishValueParser};
+use crate::audio::AudioOutput;
+use crate::chip8::{Platform, MAX_STACK_SIZE};
+use crate::debugger::{DebuggerState, RUN_SPEED_RANGE};
+use crate::palette::Palette;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "A CHIP-8 emulator and debugger")]
+pub struct Args {
+    /// ROM to load. Without one the emulator starts paused
+    pub rom: Option<PathBuf>,
+
+    /// Machine to emulate, sets the quirks: vip, modern or schip
+    #[arg(long)]
+    pub platform: Option<Platform>,
+
+    /// Override the platform's shift quirk (8xy6/8xyE shift Vx in place)
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    pub shift_quirk: Option<bool>,
+
+    /// Override the platform's load/store quirk (Fx55/Fx65 move I)
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    pub load_store_quirk: Option<bool>,
+
+    /// Override the platform's key wait quirk (Fx0A finishes on press instead of release)
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    pub key_press_quirk: Option<bool>,
+
+    /// Override the platform's stack depth (most nested subroutine calls)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=MAX_STACK_SIZE as i64))]
+    pub stack_depth: Option<u8>,
+
+    /// Override whether memory accesses past 0xFFF wrap around (on) or stop with an error (off)
+    #[arg(long, value_name = "on|off", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    pub memory_wrap: Option<bool>,
+
+    /// Run speed multiplier, from 0.001 to 500
+    #[arg(long, value_parser = parse_speed)]
+    pub speed: Option<f32>,
+
+    /// Instructions run per frame at 1x speed
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub ipf: Option<u32>,
+
+    /// Initial size of a display pixel in the window
+    #[arg(long, default_value_t = 8.0, value_parser = parse_scale)]
+    pub scale: f32,
+
+    /// Display palette: classic, amber, green or lcd
+    #[arg(long)]
+    pub palette: Option<Palette>,
+
+    /// Start paused
+    #[arg(long)]
+    pub paused: bool,
+
+    /// Seed for the random number generator, for reproducible runs
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Run without a frontend for --frames frames, then print the display and registers
+    #[arg(long, conflicts_with = "tui")]
+    pub headless: bool,
+
+    /// Number of frames to run with --headless or --export-sound
+    #[arg(long, default_value_t = 600)]
+    pub frames: u32,
+
+    /// Print every instruction as it runs (to chip8.log with --tui)
+    #[arg(long)]
+    pub trace: bool,
+
+    /// Reload the ROM whenever it changes on disk
+    #[arg(long)]
+    pub watch: bool,
+
+    /// Restore a save state after loading the ROM
+    #[arg(long, value_name = "FILE")]
+    pub load_state: Option<PathBuf>,
+
+    /// Run in the terminal instead of opening a window
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Audio output: auto, cpal, none or wav:<file>
+    #[arg(long, default_value = "auto")]
+    pub audio: AudioOutput,
+
+    /// Run headless with no input and write the sound to a WAV file
+    #[arg(long, value_name = "FILE")]
+    pub export_sound: Option<PathBuf>
+}
+
+fn parse_speed(arg: &str) -> Result<f32, String> {
+    let speed: f32 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !RUN_SPEED_RANGE.contains(&speed) {
+        return Err(format!("must be from {} to {}", RUN_SPEED_RANGE.start(), RUN_SPEED_RANGE.end()));
+    }
+    Ok(speed)
+}
+
+fn parse_scale(arg: &str) -> Result<f32, String> {
+    let scale: f32 = arg.parse().map_err(|e| format!("{}", e))?;
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err("must be more than 0".to_string());
+    }
+    Ok(scale)
+}
+
+impl Args {
+    // Overrides saved settings with whatever was given on the command line.
+    pub fn apply(&self, debugger: &mut DebuggerState) {
+        if let Some(platform) = self.platform {
+            debugger.platform = platform;
+            debugger.quirks = platform.quirks();
+        }
+        if let Some(shift) = self.shift_quirk {
+            debugger.quirks.shift = shift;
+        }
+        if let Some(ldi) = self.load_store_quirk {
+            debugger.quirks.ldi = ldi;
+        }
+        if let Some(key_press) = self.key_press_quirk {
+            debugger.quirks.key_press = key_press;
+        }
+        if let Some(depth) = self.stack_depth {
+            debugger.quirks.stack_depth = depth as usize;
+        }
+        if let Some(wrap) = self.memory_wrap {
+            debugger.quirks.memory_wrap = wrap;
+        }
+        if let Some(speed) = self.speed {
+            debugger.run_speed = speed;
+        }
+        if let Some(ipf) = self.ipf {
+            debugger.ticks_per_frame = ipf;
+        }
+        if let Some(palette) = self.palette {
+            debugger.palette = palette;
+        }
+        debugger.paused |= self.paused;
+        debugger.watch_rom |= self.watch;
+    }
+}
+
+// The settings from before and after the command line overrode them, so
+// one-off options like --speed aren't saved as the new defaults.
+#[derive(Clone)]
+pub struct Overrides {
+    pub saved: DebuggerState,
+    pub overridden: DebuggerState
+}
+
+impl Overrides {
+    // The settings to save: each overridden one goes back to its saved value,
+    // unless it's been changed since.
+    pub fn remove(&self, debugger: &DebuggerState) -> DebuggerState {
+        let (saved, overridden) = (&self.saved, &self.overridden);
+        let mut debugger = debugger.clone();
+        restore(saved.platform, overridden.platform, &mut debugger.platform);
+        restore(saved.quirks.shift, overridden.quirks.shift, &mut debugger.quirks.shift);
+        restore(saved.quirks.ldi, overridden.quirks.ldi, &mut debugger.quirks.ldi);
+        restore(saved.quirks.key_press, overridden.quirks.key_press, &mut debugger.quirks.key_press);
+        restore(saved.quirks.stack_depth, overridden.quirks.stack_depth, &mut debugger.quirks.stack_depth);
+        restore(saved.quirks.memory_wrap, overridden.quirks.memory_wrap, &mut debugger.quirks.memory_wrap);
+        restore(saved.run_speed, overridden.run_speed, &mut debugger.run_speed);
+        restore(saved.ticks_per_frame, overridden.ticks_per_frame, &mut debugger.ticks_per_frame);
+        restore(saved.palette, overridden.palette, &mut debugger.palette);
+        restore(saved.watch_rom, overridden.watch_rom, &mut debugger.watch_rom);
+        debugger
+    }
+}
+
+fn restore<T: PartialEq>(saved: T, overridden: T, current: &mut T) {
+    if *current == overridden {
+        *current = saved;
+    }
+}
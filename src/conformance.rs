@@ -0,0 +1,174 @@
+// Regression tests running the well-known test ROMs and comparing the
+// display against golden images.
+//
+// The ROMs live in tests/roms:
+//
+//     ops.ch8, which is checked in, with its listing in ops.txt
+//     2-ibm-logo.ch8, 3-corax+.ch8, 4-flags.ch8, 5-quirks.ch8 and
+//     6-keypad.ch8 from Timendus' chip8-test-suite, which tests/fetch-roms.sh
+//     downloads, and BC_test.ch8, which has to be added by hand
+//
+// Each one is run headlessly on every platform preset and its display is
+// compared against tests/golden/<rom>.<platform>.txt, which is 32 lines of
+// 64 '#' or '.' characters. The downloaded ROMs are skipped if they aren't
+// there; set CHIP8_REQUIRE_ROMS to make that a failure. A missing golden
+// image is always a failure. CHIP8_BLESS writes the current displays as the
+// goldens, which are only worth checking in once they've been compared
+// against another emulator or worked out by hand.
+
+use std::{env, fs, path::PathBuf};
+
+use crate::audio::NullAudio;
+use crate::chip8::{get_pixel, FrameBuffer, Platform, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::headless::{self, DEFAULT_SEED};
+
+// Test ROMs want to run faster than games do.
+const TICKS_PER_FRAME: u32 = 100;
+// The Timendus ROMs check this address to skip their menus.
+const SELECT_ADDR: usize = 0x1FF;
+
+struct TestRom {
+    file: &'static str,
+    frames: u32,
+    select: fn(Platform) -> Option<u8>,     // what to put at SELECT_ADDR
+    keys: &'static [(u32, u16)],            // keys held from each frame onwards
+    checked_in: bool                        // always there, rather than downloaded
+}
+
+const TEST_ROMS: [TestRom; 7] = [
+    // arithmetic, flags, skips and memory, printed in decimal
+    TestRom { file: "ops.ch8", frames: 120, select: |_| None, keys: &[], checked_in: true },
+    TestRom { file: "2-ibm-logo.ch8", frames: 30, select: |_| None, keys: &[], checked_in: false },
+    TestRom { file: "3-corax+.ch8", frames: 60, select: |_| None, keys: &[], checked_in: false },
+    TestRom { file: "4-flags.ch8", frames: 120, select: |_| None, keys: &[], checked_in: false },
+    TestRom { file: "5-quirks.ch8", frames: 600, select: quirks_select, keys: &[], checked_in: false },
+    // Ex9E test, with a few keys pressed and let go
+    TestRom { file: "6-keypad.ch8", frames: 90, select: |_| Some(1), keys: &[(30, 0x0021), (60, 0x8400), (75, 0)], checked_in: false },
+    TestRom { file: "BC_test.ch8", frames: 120, select: |_| None, keys: &[], checked_in: false },
+];
+
+// The quirks ROM's own names for the platforms.
+fn quirks_select(platform: Platform) -> Option<u8> {
+    Some(match platform {
+        Platform::Vip => 1,
+        Platform::Schip => 2,
+        Platform::Modern => 3
+    })
+}
+
+fn test_dir(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join(name)
+}
+
+fn golden_path(rom: &TestRom, platform: Platform) -> PathBuf {
+    let platform = format!("{:?}", platform).to_lowercase();
+    test_dir("golden").join(format!("{}.{}.txt", rom.file, platform))
+}
+
+fn run_rom(rom: &TestRom, bytes: &[u8], platform: Platform) -> Result<FrameBuffer, String> {
+    let mut chip8 = headless::new_machine(bytes, DEFAULT_SEED).map_err(|e| e.to_string())?;
+    chip8.quirks_mode = platform.quirks();
+    chip8.platform = platform;
+    if let Some(select) = (rom.select)(platform) {
+        chip8.memory[SELECT_ADDR] = select;
+        chip8.memory_changed();
+    }
+    let mut keys = 0;
+    for frame in 0..rom.frames {
+        if let Some(&(_, held)) = rom.keys.iter().find(|&&(start, _)| start == frame) {
+            keys = held;
+        }
+        headless::run_frames(&mut chip8, 1, TICKS_PER_FRAME, keys, &mut NullAudio)
+            .map_err(|e| format!("faulted on frame {}: {}", frame, e))?;
+    }
+    Ok(chip8.frame_buffer)
+}
+
+fn to_text(frame_buffer: &FrameBuffer) -> String {
+    let mut text = String::new();
+    for y in 0..SCREEN_HEIGHT {
+        for x in 0..SCREEN_WIDTH {
+            text.push(if get_pixel(frame_buffer, x, y) { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// Expected and actual displays side by side, with a third column marking
+// pixels that should be on ('-') or off ('+').
+fn diff(expected: &str, actual: &str) -> String {
+    let mut out = format!("{:<w$}  {:<w$}  diff\n", "expected", "actual", w = SCREEN_WIDTH);
+    let mut different = 0;
+    for (expected, actual) in expected.lines().zip(actual.lines()) {
+        let marks: String = expected.chars().zip(actual.chars()).map(|pair| match pair {
+            ('#', '.') => '-',
+            ('.', '#') => '+',
+            _ => ' '
+        }).collect();
+        different += marks.chars().filter(|&c| c != ' ').count();
+        out += format!("{}  {}  {}", expected, actual, marks).trim_end();
+        out.push('\n');
+    }
+    out + &format!("{} pixels differ\n", different)
+}
+
+#[test]
+fn test_roms_match_golden_images() {
+    let bless = env::var_os("CHIP8_BLESS").is_some();
+    let require_all = env::var_os("CHIP8_REQUIRE_ROMS").is_some();
+    let mut failures = vec![];
+    for rom in &TEST_ROMS {
+        let bytes = match fs::read(test_dir("roms").join(rom.file)) {
+            Ok(bytes) => bytes,
+            Err(_) if !rom.checked_in && !require_all => {
+                eprintln!("Skipping {}, it isn't in tests/roms", rom.file);
+                continue;
+            }
+            Err(e) => {
+                failures.push(format!("{}: {}, run tests/fetch-roms.sh or add it by hand", rom.file, e));
+                continue;
+            }
+        };
+        for platform in Platform::ALL {
+            let name = format!("{} on {}", rom.file, platform.name());
+            let actual = match run_rom(rom, &bytes, platform) {
+                Ok(frame_buffer) => to_text(&frame_buffer),
+                Err(e) => {
+                    failures.push(format!("{}: {}", name, e));
+                    continue;
+                }
+            };
+            let path = golden_path(rom, platform);
+            if bless {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, &actual).unwrap();
+                continue;
+            }
+            match fs::read_to_string(&path) {
+                Ok(expected) if expected == actual => {}
+                Ok(expected) => failures.push(format!("{} doesn't match {}:\n{}", name, path.display(), diff(&expected, &actual))),
+                Err(e) => failures.push(format!("{}: no golden image at {} ({}), see the top of conformance.rs", name, path.display(), e))
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// The harness itself, on a ROM small enough to write here.
+#[test]
+fn golden_text_round_trip() {
+    // draws the font's 0 at the top left and stops
+    let program = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+    let rom = TestRom { file: "zero.ch8", frames: 2, select: |_| None, keys: &[], checked_in: true };
+    let frame_buffer = run_rom(&rom, &program, Platform::Modern).unwrap();
+    let text = to_text(&frame_buffer);
+    assert!(text.starts_with("####...."));
+    assert_eq!(text.lines().count(), SCREEN_HEIGHT);
+    assert_eq!(text.matches('#').count(), 14);
+
+    let mut other: FrameBuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT / 8];
+    other[0] = 0b1000_0000;
+    let report = diff(&text, &to_text(&other));
+    assert!(report.ends_with("13 pixels differ\n"));
+}
@@ -1,28 +1,192 @@
-use crate::chip8::QuirksMode;
+use std::{collections::HashMap, ops::RangeInclusive, path::{Path, PathBuf}, sync::mpsc::{SendError, Sender}};
 
+use crate::beep::ToneSettings;
+use crate::chip8::{ChipError, Platform, QuirksMode};
+use crate::headless::DEFAULT_TICKS_PER_FRAME;
+use crate::gui::ScaleMode;
+use crate::palette::Palette;
+use crate::phosphor::RenderMode;
+use crate::recorder::{RecordingFormat, RecordingSettings};
+use crate::settings::MAX_RECENT_ROMS;
+
+// Speed multipliers the frame timing can cope with.
+pub const RUN_SPEED_RANGE: RangeInclusive<f32> = 0.001..=500.0;
 
 #[derive(Clone)]
 pub struct DebuggerState {
     pub run_speed: f32,
+    pub ticks_per_frame: u32,   // instructions per frame at 1x speed
     pub paused: bool,
     pub register_scroll: i32,
-    pub quirks: QuirksMode
+    pub platform: Platform,
+    pub quirks: QuirksMode,
+    pub palette: Palette,
+    pub rom_palettes: HashMap<String, Palette>,    // per-ROM overrides of palette
+    pub rom_path: Option<String>,
+    pub recent_roms: Vec<String>,       // most recently opened first
+    pub last_rom_dir: Option<PathBuf>,  // where the file dialog starts
+    pub library_dirs: Vec<PathBuf>,     // folders shown in the ROM library
+    pub watch_rom: bool,                // reload the ROM when it changes on disk
+    pub render_mode: RenderMode,
+    pub phosphor_decay: f32,
+    pub scale_mode: ScaleMode,
+    pub screenshot_scale: usize,
+    pub recording_format: RecordingFormat,
+    pub recording_audio: bool,
+    pub recording: Option<RecordingSettings>,  // set while a recording is in progress
+    pub tone: ToneSettings,
+    pub muted: bool
 }
 
 impl Default for DebuggerState {
     fn default() -> Self {
         Self {
             run_speed: 1.0,
+            ticks_per_frame: DEFAULT_TICKS_PER_FRAME,
             paused: false,
             register_scroll: 0,
-            quirks: QuirksMode::default()
+            platform: Platform::default(),
+            quirks: QuirksMode::default(),
+            palette: Palette::default(),
+            rom_palettes: HashMap::new(),
+            rom_path: None,
+            recent_roms: vec![],
+            last_rom_dir: None,
+            library_dirs: vec![],
+            watch_rom: false,
+            render_mode: RenderMode::Raw,
+            phosphor_decay: 0.5,
+            scale_mode: ScaleMode::Integer,
+            screenshot_scale: 8,
+            recording_format: RecordingFormat::Gif,
+            recording_audio: false,
+            recording: None,
+            tone: ToneSettings::default(),
+            muted: false
+        }
+    }
+}
+
+impl DebuggerState {
+    // Palette used for the loaded ROM, taking overrides into account.
+    pub fn active_palette(&self) -> Palette {
+        self.rom_path.as_ref()
+            .and_then(|path| self.rom_palettes.get(path))
+            .copied()
+            .unwrap_or(self.palette)
+    }
+
+    // Remembers a ROM that was just opened.
+    pub fn opened_rom(&mut self, path: &str) {
+        self.rom_path = Some(path.to_string());
+        self.recent_roms.retain(|recent| recent != path);
+        self.recent_roms.insert(0, path.to_string());
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+        if let Some(dir) = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            self.last_rom_dir = Some(dir.to_path_buf());
+        }
+    }
+
+    // Puts the settings back to their defaults, keeping what's going on right now
+    // and the user's ROMs.
+    pub fn restore_defaults(&mut self) {
+        *self = DebuggerState {
+            paused: self.paused,
+            rom_path: self.rom_path.take(),
+            recent_roms: std::mem::take(&mut self.recent_roms),
+            last_rom_dir: self.last_rom_dir.take(),
+            library_dirs: std::mem::take(&mut self.library_dirs),
+            recording: self.recording.take(),
+            ..DebuggerState::default()
+        };
+    }
+
+    // Tone to play, taking muting into account.
+    pub fn active_tone(&self) -> ToneSettings {
+        if self.muted {
+            ToneSettings { volume: 0.0, ..self.tone }
+        } else {
+            self.tone
+        }
+    }
+}
+
+// The settings the emulator thread runs with. Frontends keep a copy of what
+// they last sent, and send whatever's changed since.
+#[derive(Clone, PartialEq)]
+pub struct EmulatorSettings {
+    pub paused: bool,
+    pub run_speed: f32,
+    pub ticks_per_frame: u32,
+    pub platform: Platform,
+    pub quirks: QuirksMode,
+    pub tone: ToneSettings,     // with muting taken into account
+    pub recording: Option<RecordingSettings>,
+    pub phosphor_decay: f32
+}
+
+impl EmulatorSettings {
+    pub fn new(debugger: &DebuggerState) -> Self {
+        EmulatorSettings {
+            paused: debugger.paused,
+            run_speed: debugger.run_speed,
+            ticks_per_frame: debugger.ticks_per_frame,
+            platform: debugger.platform,
+            quirks: debugger.quirks,
+            tone: debugger.active_tone(),
+            recording: debugger.recording.clone(),
+            phosphor_decay: debugger.phosphor_decay
         }
     }
+
+    // Sends the emulator whatever's changed in `debugger` since last time.
+    // Fails if the emulator thread has stopped.
+    pub fn sync(&mut self, debugger: &DebuggerState, sender: &Sender<DebugInstructions>) -> Result<(), SendError<DebugInstructions>> {
+        let new = EmulatorSettings::new(debugger);
+        if new.paused != self.paused {
+            sender.send(DebugInstructions::SetPaused(new.paused))?;
+        }
+        if (new.run_speed, new.ticks_per_frame) != (self.run_speed, self.ticks_per_frame) {
+            sender.send(DebugInstructions::SetSpeed(new.run_speed, new.ticks_per_frame))?;
+        }
+        if (new.platform, new.quirks) != (self.platform, self.quirks) {
+            sender.send(DebugInstructions::SetQuirks(new.platform, new.quirks))?;
+        }
+        if new.tone != self.tone {
+            sender.send(DebugInstructions::SetTone(new.tone))?;
+        }
+        if new.recording != self.recording {
+            sender.send(DebugInstructions::SetRecording(new.recording.clone()))?;
+        }
+        if new.phosphor_decay != self.phosphor_decay {
+            sender.send(DebugInstructions::SetPhosphorDecay(new.phosphor_decay))?;
+        }
+        *self = new;
+        Ok(())
+    }
+}
+
+// Information the emulator thread passes back to the frontend.
+#[derive(Clone, Default)]
+pub struct EmulatorStatus {
+    pub audio_backend: String,
+    pub audio_error: Option<String>
 }
 
 pub enum DebugInstructions {
     Step,
     Frame,
     Reset,
-    Reload(String)
+    Reload(String),
+    // Skip the instruction that caused the fault and carry on
+    ContinueAsNop,
+    // Restore a save state, replying with whether it worked
+    LoadState(Vec<u8>, Sender<Result<(), ChipError>>),
+    SetPaused(bool),
+    SetSpeed(f32, u32),     // run speed and instructions per frame
+    SetQuirks(Platform, QuirksMode),
+    SetTone(ToneSettings),
+    // Start recording, or finish the recording in progress
+    SetRecording(Option<RecordingSettings>),
+    SetPhosphorDecay(f32)   // fraction of brightness lost per frame
 }
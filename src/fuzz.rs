@@ -0,0 +1,403 @@
+// Fuzzing the interpreter with random ROMs and key presses.
+//
+// Every ROM is run on the real interpreter and on a deliberately simple
+// reference one written straight from the instruction descriptions, and the
+// two have to agree on the registers, memory and display, and on when they
+// stop with an error. The real one must never panic on anything it's fed.
+//
+// A few hundred ROMs run as part of cargo test. For longer runs set
+// CHIP8_FUZZ_ITERATIONS, and CHIP8_FUZZ_SEED to repeat a failing run. For
+// coverage-guided fuzzing, `cargo fuzz run differential` in fuzz/ feeds
+// run_input with whatever libFuzzer comes up with.
+
+use std::env;
+
+use rand::{Rng, RngCore};
+use rand_pcg::Pcg32;
+use crate::chip8::{get_pixel, Chip8, ChipError, QuirksMode, MAX_STACK_SIZE, MEMORY_SIZE, REGISTER_COUNT, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const DEFAULT_ITERATIONS: u64 = 300;
+const MAX_FRAMES: u32 = 60;
+const TICKS_PER_FRAME: u32 = 20;
+const ROM_START: usize = 0x200;
+
+// The interpreter as plainly as it can be written, to check the real one
+// against. Faults compare by their Debug text.
+struct Reference {
+    memory: [u8; MEMORY_SIZE],
+    v: [u8; REGISTER_COUNT],
+    i: u16,
+    pc: u16,
+    stack: Vec<u16>,
+    dt: u8,
+    st: u8,
+    display: [[bool; SCREEN_WIDTH]; SCREEN_HEIGHT],
+    held_key: Option<u8>,   // key pressed during Fx0A, waiting for it to be let go
+    rng: Pcg32,
+    quirks: QuirksMode
+}
+
+impl Reference {
+    fn new(memory: [u8; MEMORY_SIZE], rng: Pcg32, quirks: QuirksMode) -> Self {
+        Reference {
+            memory,
+            v: [0; REGISTER_COUNT],
+            i: 0,
+            pc: ROM_START as u16,
+            stack: vec![],
+            dt: 0,
+            st: 0,
+            display: [[false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            held_key: None,
+            rng,
+            quirks
+        }
+    }
+
+    // Address `offset` bytes after I, failing if the last byte the
+    // instruction touches is out of range and memory doesn't wrap.
+    fn addr(&self, offset: usize, last: usize) -> Result<usize, ChipError> {
+        let last = self.i as usize + last;
+        if last >= MEMORY_SIZE && !self.quirks.memory_wrap {
+            return Err(ChipError::MemoryAccessError(self.pc, last));
+        }
+        Ok((self.i as usize + offset) % MEMORY_SIZE)
+    }
+
+    fn step(&mut self, keys: u16) -> Result<(), ChipError> {
+        let pc = self.pc as usize;
+        if pc + 1 >= MEMORY_SIZE {
+            return Err(ChipError::ProgramCounterError(self.pc));
+        }
+        let opcode = u16::from_be_bytes([self.memory[pc], self.memory[pc + 1]]);
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let kk = opcode as u8;
+        let nnn = opcode & 0xFFF;
+        let bad = ChipError::BadOperationError((opcode >> 8) as u8, kk);
+        let mut next = self.pc + 2;
+
+        match opcode >> 12 {
+            0x0 if opcode == 0x00E0 => self.display = [[false; SCREEN_WIDTH]; SCREEN_HEIGHT],
+            0x0 if opcode == 0x00EE => next = self.stack.pop().ok_or(ChipError::EmptyStackError)?,
+            0x1 => next = nnn,
+            0x2 => {
+                if self.stack.len() >= self.quirks.stack_depth.min(MAX_STACK_SIZE) {
+                    return Err(ChipError::FullStackError);
+                }
+                self.stack.push(next);
+                next = nnn;
+            }
+            0x3 if self.v[x] == kk => next += 2,
+            0x4 if self.v[x] != kk => next += 2,
+            0x5 if n == 0 => if self.v[x] == self.v[y] { next += 2 },
+            0x6 => self.v[x] = kk,
+            0x7 => self.v[x] = self.v[x].wrapping_add(kk),
+            0x8 => {
+                let (vx, vy) = (self.v[x], self.v[y]);
+                let shifted = if self.quirks.shift { vy } else { vx };
+                let (val, flag) = match n {
+                    0x0 => (vy, None),
+                    0x1 => (vx | vy, None),
+                    0x2 => (vx & vy, None),
+                    0x3 => (vx ^ vy, None),
+                    0x4 => (vx.wrapping_add(vy), Some((vx as u16 + vy as u16 > 0xFF) as u8)),
+                    0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+                    0x6 => (shifted >> 1, Some(shifted & 1)),
+                    0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+                    0xE => (shifted << 1, Some(shifted >> 7)),
+                    _ => return Err(bad)
+                };
+                self.v[x] = val;
+                if let Some(flag) = flag {
+                    self.v[0xF] = flag;
+                }
+            }
+            0x9 if n == 0 => if self.v[x] != self.v[y] { next += 2 },
+            0xA => self.i = nnn,
+            0xB => next = self.v[0] as u16 + nnn,
+            0xC => self.v[x] = self.rng.next_u32() as u8 & kk,
+            0xD => {
+                let mut collision = false;
+                for row in 0..n {
+                    let sprite = self.memory[self.addr(row, n - 1)?];
+                    for bit in 0..8 {
+                        if sprite & (0x80 >> bit) > 0 {
+                            let pixel = &mut self.display[(self.v[y] as usize + row) % SCREEN_HEIGHT][(self.v[x] as usize + bit) % SCREEN_WIDTH];
+                            collision |= *pixel;
+                            *pixel = !*pixel;
+                        }
+                    }
+                }
+                self.v[0xF] = collision as u8;
+            }
+            0xE if kk == 0x9E => if keys >> (self.v[x] & 0xF) & 1 == 1 { next += 2 },
+            0xE if kk == 0xA1 => if keys >> (self.v[x] & 0xF) & 1 == 0 { next += 2 },
+            0xF => match kk {
+                0x07 => self.v[x] = self.dt,
+                0x0A => match self.held_key {
+                    Some(key) if keys >> key & 1 == 0 => {
+                        self.v[x] = key;
+                        self.held_key = None;
+                    }
+                    Some(_) => next = self.pc,
+                    None if keys == 0 => next = self.pc,
+                    None => {
+                        let key = keys.trailing_zeros() as u8;
+                        if self.quirks.key_press {
+                            self.v[x] = key;
+                        } else {
+                            self.held_key = Some(key);
+                            next = self.pc;
+                        }
+                    }
+                },
+                0x15 => self.dt = self.v[x],
+                0x18 => self.st = self.v[x],
+                0x1E => self.i = self.i.wrapping_add(self.v[x] as u16),
+                0x29 => self.i = (self.v[x] & 0xF) as u16 * 5,
+                0x33 => {
+                    let digits = [self.v[x] / 100, self.v[x] / 10 % 10, self.v[x] % 10];
+                    for (offset, digit) in digits.into_iter().enumerate() {
+                        let addr = self.addr(offset, 2)?;
+                        self.memory[addr] = digit;
+                    }
+                }
+                0x55 | 0x65 => {
+                    for reg in 0..=x {
+                        let addr = self.addr(reg, x)?;
+                        if kk == 0x55 {
+                            self.memory[addr] = self.v[reg];
+                        } else {
+                            self.v[reg] = self.memory[addr];
+                        }
+                    }
+                    if self.quirks.ldi {
+                        self.i = self.i.wrapping_add(x as u16 + 1);
+                    }
+                }
+                _ => return Err(bad)
+            },
+            0x3 | 0x4 => {}
+            _ => return Err(bad)
+        }
+        self.pc = next;
+        Ok(())
+    }
+
+    fn frame(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+    }
+}
+
+// Describes the first difference between the two machines, if there is one.
+fn compare(chip8: &Chip8, reference: &Reference, with_display: bool) -> Option<String> {
+    if chip8.registers != reference.v {
+        return Some(format!("registers {:02x?} != {:02x?}", chip8.registers, reference.v));
+    }
+    if (chip8.pc, chip8.ir, chip8.dt) != (reference.pc, reference.i, reference.dt) {
+        return Some(format!("pc/I/dt {:03x} {:03x} {} != {:03x} {:03x} {}",
+            chip8.pc, chip8.ir, chip8.dt, reference.pc, reference.i, reference.dt));
+    }
+    if chip8.stack() != reference.stack {
+        return Some(format!("stack {:03x?} != {:03x?}", chip8.stack(), reference.stack));
+    }
+    if let Some(addr) = (0..MEMORY_SIZE).find(|&addr| chip8.memory[addr] != reference.memory[addr]) {
+        return Some(format!("memory at {:03x}: {:02x} != {:02x}", addr, chip8.memory[addr], reference.memory[addr]));
+    }
+    if with_display {
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                if get_pixel(&chip8.frame_buffer, x, y) != reference.display[y][x] {
+                    return Some(format!("pixel ({}, {})", x, y));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Mostly real instructions with small operands, jumping around inside the
+// ROM so programs run for a while, plus some arbitrary words.
+fn random_instruction(rng: &mut Pcg32, rom_len: usize) -> u16 {
+    let x = rng.gen_range(0..16u16) << 8;
+    let y = rng.gen_range(0..16u16) << 4;
+    let target = ((ROM_START + rng.gen_range(0..rom_len.max(2))) & !1) as u16;
+    let kk = rng.gen::<u8>() as u16;
+    match rng.gen_range(0..20) {
+        0 => 0x00E0,
+        1 => 0x00EE,
+        2 => 0x1000 | target,
+        3 => 0x2000 | target,
+        4 => 0x3000 | x | kk,
+        5 => 0x4000 | x | kk,
+        6 => 0x5000 | x | y,
+        7 => 0x6000 | x | kk,
+        8 => 0x7000 | x | kk,
+        9 => 0x8000 | x | y | [0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE][rng.gen_range(0..9)],
+        10 => 0x9000 | x | y,
+        11 => 0xA000 | rng.gen_range(0..0x1000),
+        12 => 0xB000 | (target.wrapping_sub(rng.gen_range(0..16)) & 0xFFF),
+        13 => 0xC000 | x | kk,
+        14 => 0xD000 | x | y | rng.gen_range(0..16),
+        15 => 0xE000 | x | [0x9E, 0xA1][rng.gen_range(0..2)],
+        16 | 17 => 0xF000 | x | [0x07, 0x0A, 0x15, 0x18, 0x1E, 0x29, 0x33, 0x55, 0x65][rng.gen_range(0..9)],
+        _ => rng.gen()
+    }
+}
+
+fn random_rom(rng: &mut Pcg32) -> Vec<u8> {
+    if rng.gen_bool(0.2) {
+        // arbitrary bytes, now and then too many to fit in memory
+        let len = if rng.gen_bool(0.1) { rng.gen_range(0..MEMORY_SIZE * 2) } else { rng.gen_range(0..128) };
+        return (0..len).map(|_| rng.gen()).collect();
+    }
+    let len = rng.gen_range(1..64) * 2;
+    (0..len / 2).flat_map(|_| random_instruction(rng, len).to_be_bytes()).collect()
+}
+
+fn random_quirks(rng: &mut Pcg32) -> QuirksMode {
+    QuirksMode {
+        shift: rng.gen(),
+        ldi: rng.gen(),
+        key_press: rng.gen(),
+        stack_depth: rng.gen_range(1..=MAX_STACK_SIZE),
+        memory_wrap: rng.gen()
+    }
+}
+
+// Keys held for a frame: usually none, sometimes one, sometimes anything.
+fn random_keys(rng: &mut Pcg32) -> u16 {
+    match rng.gen_range(0..4) {
+        0 => 1 << rng.gen_range(0..16),
+        1 => rng.gen(),
+        _ => 0
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    env::var(name).ok().and_then(|val| val.parse().ok())
+}
+
+// Runs one random ROM on both interpreters, returning what went wrong.
+fn run_one(seed: u64) -> Result<(), String> {
+    let mut rng = Pcg32::new(seed, 0);
+    let rom = random_rom(&mut rng);
+    let quirks = random_quirks(&mut rng);
+    let keys: Vec<u16> = (0..rng.gen_range(1..=MAX_FRAMES)).map(|_| random_keys(&mut rng)).collect();
+    run_program(&rom, quirks, &keys, seed)
+}
+
+// Splits fuzzer input into a quirks byte, a stack depth, a frame count, the
+// keys held on each of those frames and then the ROM, and runs it on both
+// interpreters. Input too short for all that is a ROM with no keys.
+pub fn run_input(data: &[u8]) -> Result<(), String> {
+    let (quirks, stack_depth, frames, rest) = match data {
+        [quirks, stack_depth, frames, rest @ ..] => (*quirks, *stack_depth, *frames, rest),
+        _ => return run_program(data, QuirksMode::default(), &[0], 0)
+    };
+    let quirks = QuirksMode {
+        shift: quirks & 1 != 0,
+        ldi: quirks & 2 != 0,
+        key_press: quirks & 4 != 0,
+        stack_depth: stack_depth as usize % MAX_STACK_SIZE + 1,
+        memory_wrap: quirks & 8 != 0
+    };
+    let frames = (frames as usize % MAX_FRAMES as usize + 1).min(rest.len() / 2);
+    let (keys, rom) = rest.split_at(frames * 2);
+    let keys: Vec<u16> = keys.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+    run_program(rom, quirks, if keys.is_empty() { &[0] } else { &keys }, 0)
+}
+
+// Runs a ROM on both interpreters, with keys[i] held on frame i, returning
+// the first difference.
+fn run_program(rom: &[u8], quirks: QuirksMode, keys: &[u16], seed: u64) -> Result<(), String> {
+    let mut chip8 = Chip8::new(Pcg32::new(seed, 1));
+    chip8.quirks_mode = quirks;
+    if chip8.load(rom).is_err() {
+        return if rom.len() > MEMORY_SIZE - ROM_START { Ok(()) } else { Err("load failed".to_string()) };
+    }
+    let mut reference = Reference::new(chip8.memory, Pcg32::new(seed, 1), quirks);
+
+    for (frame, &keys) in keys.iter().enumerate() {
+        for _ in 0..TICKS_PER_FRAME {
+            let opcode = chip8.memory.get(chip8.pc as usize..chip8.pc as usize + 2)
+                .map(|bytes| format!("{:02x}{:02x}", bytes[0], bytes[1]))
+                .unwrap_or_default();
+            let context = format!("frame {}, pc {:03x}, opcode {}", frame, chip8.pc, opcode);
+            let expected = reference.step(keys);
+            match (chip8.tick(keys), expected) {
+                (Ok(()), Ok(())) => {}
+                (Err(fault), Err(error)) if format!("{:?}", fault.error) == format!("{:?}", error) => return Ok(()),
+                (actual, expected) => {
+                    return Err(format!("{}: got {:?}, expected {:?}", context, actual.err().map(|fault| fault.error), expected.err()));
+                }
+            }
+            if let Some(difference) = compare(&chip8, &reference, false) {
+                return Err(format!("{}: {}", context, difference));
+            }
+        }
+        chip8.frame();
+        reference.frame();
+        if let Some(difference) = compare(&chip8, &reference, true) {
+            return Err(format!("end of frame {}: {}", frame, difference));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn matches_reference_interpreter() {
+    let first = env_u64("CHIP8_FUZZ_SEED").unwrap_or(0);
+    let iterations = env_u64("CHIP8_FUZZ_ITERATIONS").unwrap_or(DEFAULT_ITERATIONS);
+    for seed in first..first + iterations {
+        if let Err(e) = run_one(seed) {
+            panic!("seed {} (rerun with CHIP8_FUZZ_SEED={} CHIP8_FUZZ_ITERATIONS=1): {}", seed, seed, e);
+        }
+    }
+}
+
+// Whatever the fuzz target is given has to make it to both interpreters.
+#[test]
+fn fuzz_input_runs() {
+    let mut rng = Pcg32::new(0, 0);
+    for len in [0, 2, 3, 4, 10, 200] {
+        let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        run_input(&data).unwrap();
+    }
+    // shift quirk on, 2 frames with key 5 then nothing held, then the ROM
+    run_input(&[0x01, 0x0F, 0x01, 0x00, 0x20, 0x00, 0x00, 0x60, 0x05, 0xE0, 0x9E, 0x12, 0x00]).unwrap();
+}
+
+#[test]
+fn load_state_never_panics() {
+    let mut rng = Pcg32::new(0, 0);
+    let mut chip8 = Chip8::new(Pcg32::new(0, 0));
+    chip8.load(&[0x12, 0x00]).unwrap();
+    let state = chip8.save_state();
+    for _ in 0..env_u64("CHIP8_FUZZ_ITERATIONS").unwrap_or(DEFAULT_ITERATIONS) {
+        let mut bytes = state.clone();
+        match rng.gen_range(0..3) {
+            0 => bytes.truncate(rng.gen_range(0..state.len())),
+            1 => bytes.push(rng.gen()),
+            _ => {}
+        }
+        for _ in 0..rng.gen_range(0..8) {
+            let len = bytes.len();
+            if len > 0 {
+                bytes[rng.gen_range(0..len)] = rng.gen();
+            }
+        }
+        if chip8.load_state(&bytes).is_ok() {
+            // whatever got loaded has to run without panicking
+            for _ in 0..100 {
+                if chip8.tick(rng.gen()).is_err() {
+                    break;
+                }
+            }
+            chip8.load_state(&state).unwrap();
+        }
+    }
+}
@@ -1,49 +1,152 @@
 
-use std::sync::mpsc::Sender;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
 
-use egui::{Context, Rect, Pos2, Rounding, Color32, Window, Vec2, Sense};
-use crate::chip8::{SCREEN_WIDTH, SCREEN_HEIGHT, INSTRUCTION_SIZE, MEMORY_SIZE, Chip8, REGISTER_COUNT};
-use crate::debugger::{DebuggerState, DebugInstructions};
-use crate::input::InputDriver;
-use crate::translator;
+use egui::{Context, Id, InnerResponse, LayerId, Order, Rect, Pos2, Rounding, Color32, Window, Vec2, Sense, Stroke, Align2, FontId, ColorImage, TextureHandle, TextureFilter, Key};
+use crate::beep::Waveform;
+use crate::chip8::{ChipError, ChipFault, SCREEN_WIDTH, SCREEN_HEIGHT, MAX_STACK_SIZE, REGISTER_COUNT, KeyWait, Platform, get_pixel};
+use crate::cli::Overrides;
+use crate::debugger::{DebuggerState, DebugInstructions, EmulatorSettings, EmulatorStatus, RUN_SPEED_RANGE};
+use crate::input::{InputDriver, KEYPAD_LAYOUT};
+use crate::library::{self, RomEntry};
+use crate::palette::{self, Palette};
+use crate::phosphor::RenderMode;
+use crate::recorder::{RecordingFormat, RecordingSettings};
+use crate::screenshot;
+use crate::settings::{Layout, Settings};
+use crate::snapshot::{Snapshot, SnapshotReceiver};
+use crate::watcher::RomWatcher;
 
 const INSTRUCTION_VIEW_RANGE: i32 = 3;
+const KEYPAD_KEY_SIZE: f32 = 40.0;
+const THUMBNAIL_SCALE: f32 = 1.5;
+const LIBRARY_HEIGHT: f32 = 300.0;
+
+// How the display is scaled to fit the game window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    // Largest whole-number scale that fits, so every pixel is the same size
+    Integer,
+    // Fill as much of the window as possible, keeping the aspect ratio
+    Fit
+}
+
+impl ScaleMode {
+    pub const ALL: [ScaleMode; 2] = [ScaleMode::Integer, ScaleMode::Fit];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Integer => "Integer",
+            ScaleMode::Fit => "Fit to window",
+        }
+    }
+
+    // Size of the display drawn inside an area of the given size.
+    fn display_size(&self, available: Vec2) -> Vec2 {
+        let fit = (available.x / SCREEN_WIDTH as f32).min(available.y / SCREEN_HEIGHT as f32);
+        let scale = match self {
+            ScaleMode::Integer => fit.floor().max(1.0),
+            ScaleMode::Fit => fit.max(1.0),
+        };
+        Vec2 { x: SCREEN_WIDTH as f32 * scale, y: SCREEN_HEIGHT as f32 * scale }
+    }
+}
+
+// Settings the display texture was last drawn with, to tell when to redraw it.
+#[derive(PartialEq)]
+struct DisplayKey {
+    palette: Palette,
+    render_mode: RenderMode,
+    frame_count: u64,
+    display_version: u64
+}
 
 pub struct ChipGUI {
     scale: f32,
     input_mutex: Arc<Mutex<u16>>,
-    chip8: Arc<Mutex<Chip8>>,
-    debugger_mutex: Arc<Mutex<DebuggerState>>,
+    snapshots: SnapshotReceiver,
+    snapshot: Arc<Snapshot>,    // the machine as of this repaint
     debugger: DebuggerState,
-    debug_sender: Sender<DebugInstructions>
+    sent_settings: EmulatorSettings,    // what the emulator was last told
+    debug_sender: Sender<DebugInstructions>,
+    status: Arc<Mutex<EmulatorStatus>>,
+    keypad_clicked: u16,
+    display_texture: Option<TextureHandle>,
+    display_key: Option<DisplayKey>,
+    status_message: Option<String>,
+    layout: Layout,
+    overrides: Overrides,   // command line options, which aren't saved
+    library: Vec<RomEntry>,
+    library_scan: Option<Receiver<Vec<RomEntry>>>,     // set while the library is being scanned
+    state_load: Option<(PathBuf, Receiver<Result<(), ChipError>>)>,    // set while the emulator loads a save state
+    emulator_stopped: bool,     // the emulator thread has gone, so commands go nowhere
+    thumbnails: HashMap<PathBuf, TextureHandle>,
+    thumbnail_palette: Palette,                         // palette the thumbnails were drawn with
+    watcher: RomWatcher
 }
 
 impl ChipGUI {
-    pub fn new(_cc: &eframe::CreationContext<'_>, scale: f32, input_mutex: Arc<Mutex<u16>>, chip8: Arc<Mutex<Chip8>>, debugger_mutex: Arc<Mutex<DebuggerState>>, debug_sender: Sender<DebugInstructions>) -> Self {
-        let mutex_clone = {
-            let ul = debugger_mutex.lock().unwrap();
-            ul.clone()
-        };
-        ChipGUI {  
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(_cc: &eframe::CreationContext<'_>, scale: f32, layout: Layout, overrides: Overrides, input_mutex: Arc<Mutex<u16>>, mut snapshots: SnapshotReceiver, debugger: DebuggerState, debug_sender: Sender<DebugInstructions>, status: Arc<Mutex<EmulatorStatus>>) -> Self {
+        let mut gui = ChipGUI {
             scale,
             input_mutex,
-            chip8,
-            debugger_mutex,
-            debugger: mutex_clone,
-            debug_sender
-        }
+            snapshot: snapshots.latest(),
+            snapshots,
+            sent_settings: EmulatorSettings::new(&debugger),
+            debugger,
+            debug_sender,
+            status,
+            keypad_clicked: 0,
+            display_texture: None,
+            display_key: None,
+            status_message: None,
+            layout,
+            overrides,
+            library: vec![],
+            library_scan: None,
+            state_load: None,
+            emulator_stopped: false,
+            thumbnails: HashMap::new(),
+            thumbnail_palette: Palette::default(),
+            watcher: RomWatcher::new()
+        };
+        gui.scan_library();
+        gui
     }
 }
 
 impl eframe::App for ChipGUI {
-    fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
-        {
-            let all_input = ctx.input();
-            {
-                let mut input_lock = self.input_mutex.lock().unwrap();
-                *input_lock = InputDriver::convert_keys(&all_input.keys_down);
+    fn update(&mut self, ctx: &Context, frame: &mut eframe::Frame) {
+        let window_info = frame.info().window_info;
+        if !window_info.fullscreen {
+            self.layout.window_pos = window_info.position;
+            self.layout.window_size = Some(window_info.size);
+        }
+
+        self.handle_dropped_files(ctx);
+        if self.debugger.watch_rom {
+            if let Some(path) = self.debugger.rom_path.clone() {
+                if self.watcher.poll(&path) {
+                    self.send(DebugInstructions::Reload(path.clone()));
+                    self.status_message = Some(format!("Reloaded {}", path));
+                }
             }
+        } else {
+            self.watcher.stop();
+        }
+
+        if ctx.input().key_pressed(Key::F12) {
+            self.save_screenshot(Path::new(&screenshot::default_file_name("png")));
+        }
+        if ctx.input().key_pressed(Key::F9) {
+            self.toggle_recording(None);
+        }
+
+        let keyboard_keys = {
+            let all_input = ctx.input();
 
             // check for scrolling
             if self.debugger.paused {
@@ -53,103 +156,638 @@ impl eframe::App for ChipGUI {
                     self.debugger.register_scroll += 1;
                 }
             }
-        }
 
-        {
-            let mut run_speed_lock = self.debugger_mutex.lock().unwrap();
-            *run_speed_lock = self.debugger.clone();
-        }
+            InputDriver::convert_keys(&all_input.keys_down)
+        };
 
-        {
-            let mut chip8 = self.chip8.lock().unwrap();
-            chip8.quirks_mode = self.debugger.quirks;
+        self.snapshot = self.snapshots.latest();
+        let snapshot = self.snapshot.clone();
+        self.check_state_load();
+        // the emulator stops on a fault, so stay paused after it's been dealt with
+        if snapshot.fault.is_some() {
+            self.debugger.paused = true;
         }
 
-        Window::new("instructions")
+        let response = self.window("instructions")
             .show(ctx, |ui| {
-                let chip8 = self.chip8.lock().unwrap();
-                self.debugger.register_scroll = self.debugger.register_scroll.clamp(-(chip8.pc as i32) + INSTRUCTION_VIEW_RANGE, chip8.pc as i32 - INSTRUCTION_VIEW_RANGE);
-                let range_min = -INSTRUCTION_VIEW_RANGE+self.debugger.register_scroll;//.clamp(0, MEMORY_SIZE as i32-INSTRUCTION_SIZE as i32-2*INSTRUCTION_VIEW_RANGE);
-                let range_max = INSTRUCTION_VIEW_RANGE+self.debugger.register_scroll;//.clamp(2*INSTRUCTION_VIEW_RANGE, MEMORY_SIZE as i32-INSTRUCTION_SIZE as i32);
+                self.debugger.register_scroll = self.debugger.register_scroll.clamp(-(snapshot.pc as i32) + INSTRUCTION_VIEW_RANGE, snapshot.pc as i32 - INSTRUCTION_VIEW_RANGE);
+                let range_min = -INSTRUCTION_VIEW_RANGE+self.debugger.register_scroll;
+                let range_max = INSTRUCTION_VIEW_RANGE+self.debugger.register_scroll;
                 for i in range_min..=range_max {
-                    let new_pc = chip8.pc as i32 + i * INSTRUCTION_SIZE as i32;
-                    if new_pc < 0 || new_pc + INSTRUCTION_SIZE as i32 >= MEMORY_SIZE as i32 {
-                        continue;
-                    }
-
-                    let cur_inst = (chip8.memory[new_pc as usize], chip8.memory[new_pc as usize+1]);
-                    let translated = translator::translate(cur_inst.0, cur_inst.1);
-                    if i == 0 {
-                        ui.code(format!("{:<30}", format!("> {:03x} {}", new_pc, translated)));
-                    } else {
-                        ui.code(format!("{:<30}", format!("  {:03x} {}", new_pc, translated)));
+                    if let Some((addr, translated)) = snapshot.disassemble(i) {
+                        let marker = if i == 0 { '>' } else { ' ' };
+                        ui.code(format!("{:<30}", format!("{} {:03x} {}", marker, addr, translated)));
                     }
                 }
             });
+        self.remember_window("instructions", response);
 
-        Window::new("controls")
+        let response = self.window("controls")
             .show(ctx, |ui| {
-                ui.add(egui::Slider::new(&mut self.debugger.run_speed, 0.001..=500.0).logarithmic(true).text("Simulation speed"));
+                ui.add(egui::Slider::new(&mut self.debugger.run_speed, RUN_SPEED_RANGE).logarithmic(true).text("Simulation speed"));
+                ui.add(egui::Slider::new(&mut self.debugger.ticks_per_frame, 1..=1000).logarithmic(true).text("Instructions per frame"));
                 ui.checkbox(&mut self.debugger.paused, "Paused");
                 if self.debugger.paused {
                     if ui.button("Step").clicked() {
-                        self.debug_sender.send(DebugInstructions::Step).unwrap();
+                        self.send(DebugInstructions::Step);
                         self.debugger.register_scroll = 0;
                     }
                     if ui.button("Frame").clicked() {
-                        self.debug_sender.send(DebugInstructions::Frame).unwrap();
+                        self.send(DebugInstructions::Frame);
                     }
                     if ui.button("Reset").clicked() {
-                        self.debug_sender.send(DebugInstructions::Reset).unwrap();
+                        self.send(DebugInstructions::Reset);
                         self.debugger.register_scroll = 0;
                     }
-                    if ui.button("Load game from file").clicked() {
-                        if let Some(path) = rfd::FileDialog::new().pick_file() {
-                            let str = path.display().to_string();
-                            self.debug_sender.send(DebugInstructions::Reload(str)).unwrap();
-                        }
-                    }
                 } else {
                     self.debugger.register_scroll = 0;
                 }
+                if ui.button("Load game from file").clicked() {
+                    let mut dialog = rfd::FileDialog::new();
+                    if let Some(dir) = &self.debugger.last_rom_dir {
+                        dialog = dialog.set_directory(dir);
+                    }
+                    if let Some(path) = dialog.pick_file() {
+                        self.load_rom(&path.display().to_string(), None);
+                    }
+                }
+                ui.checkbox(&mut self.debugger.watch_rom, "Reload when the ROM changes on disk");
+                let old_platform = self.debugger.platform;
+                egui::ComboBox::from_label("Platform")
+                    .selected_text(self.debugger.platform.name())
+                    .show_ui(ui, |ui| {
+                        for platform in Platform::ALL {
+                            ui.selectable_value(&mut self.debugger.platform, platform, platform.name());
+                        }
+                    });
+                if self.debugger.platform != old_platform {
+                    self.debugger.quirks = self.debugger.platform.quirks();
+                }
                 ui.checkbox(&mut self.debugger.quirks.ldi, "Enable loading index quirk");
                 ui.checkbox(&mut self.debugger.quirks.shift, "Enable shift behavior quirk");
+                ui.checkbox(&mut self.debugger.quirks.key_press, "Finish key wait on press instead of release");
+                ui.checkbox(&mut self.debugger.quirks.memory_wrap, "Wrap memory accesses past 0xFFF instead of stopping");
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.debugger.quirks.stack_depth).clamp_range(1..=MAX_STACK_SIZE));
+                    ui.label("Stack depth");
+                });
+
+                ui.separator();
+                let rom_path = self.debugger.rom_path.clone();
+                let mut per_rom = rom_path.as_ref().is_some_and(|path| self.debugger.rom_palettes.contains_key(path));
+                let palette = match &rom_path {
+                    Some(path) if per_rom => self.debugger.rom_palettes.get_mut(path).unwrap(),
+                    _ => &mut self.debugger.palette
+                };
+                palette_picker(ui, palette);
+                if let Some(path) = rom_path {
+                    if ui.checkbox(&mut per_rom, "Use palette for this ROM only").changed() {
+                        if per_rom {
+                            self.debugger.rom_palettes.insert(path, self.debugger.palette);
+                        } else {
+                            self.debugger.rom_palettes.remove(&path);
+                        }
+                    }
+                }
+
+                egui::ComboBox::from_label("Rendering")
+                    .selected_text(self.debugger.render_mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in RenderMode::ALL {
+                            ui.selectable_value(&mut self.debugger.render_mode, mode, mode.name());
+                        }
+                    });
+                if self.debugger.render_mode == RenderMode::Phosphor {
+                    ui.add(egui::Slider::new(&mut self.debugger.phosphor_decay, 0.01..=1.0).text("Decay per frame"));
+                }
+                egui::ComboBox::from_label("Scaling")
+                    .selected_text(self.debugger.scale_mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in ScaleMode::ALL {
+                            ui.selectable_value(&mut self.debugger.scale_mode, mode, mode.name());
+                        }
+                    });
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.debugger.muted, "Mute");
+                    egui::ComboBox::from_id_source("waveform")
+                        .selected_text(self.debugger.tone.waveform.name())
+                        .show_ui(ui, |ui| {
+                            for waveform in Waveform::ALL {
+                                ui.selectable_value(&mut self.debugger.tone.waveform, waveform, waveform.name());
+                            }
+                        });
+                });
+                ui.add(egui::Slider::new(&mut self.debugger.tone.pitch, 50.0..=2000.0).logarithmic(true).suffix(" Hz").text("Pitch"));
+                ui.add(egui::Slider::new(&mut self.debugger.tone.volume, 0.0..=1.0).text("Volume"));
+                {
+                    let status = self.status.lock().unwrap();
+                    ui.label(format!("Audio output: {}", status.audio_backend));
+                    if let Some(error) = &status.audio_error {
+                        ui.colored_label(Color32::LIGHT_RED, error);
+                    }
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Save state").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().set_file_name(&screenshot::default_file_name("c8s")).save_file() {
+                            self.save_state(&path);
+                        }
+                    }
+                    if ui.button("Load state").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            self.load_state(&path);
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Save screenshot (F12)").clicked() {
+                        let dialog = rfd::FileDialog::new()
+                            .add_filter("PNG image", &["png"])
+                            .set_file_name(&screenshot::default_file_name("png"));
+                        if let Some(path) = dialog.save_file() {
+                            self.save_screenshot(&path);
+                        }
+                    }
+                    ui.add(egui::DragValue::new(&mut self.debugger.screenshot_scale).clamp_range(1..=32).suffix("x"));
+                });
+                ui.horizontal(|ui| {
+                    if self.debugger.recording.is_some() {
+                        if ui.button("Stop recording (F9)").clicked() {
+                            self.toggle_recording(None);
+                        }
+                    } else {
+                        if ui.button("Start recording (F9)").clicked() {
+                            let path = match self.debugger.recording_format {
+                                RecordingFormat::Gif => rfd::FileDialog::new()
+                                    .add_filter("GIF image", &["gif"])
+                                    .set_file_name(&screenshot::default_file_name("gif"))
+                                    .save_file(),
+                                RecordingFormat::PngSequence => rfd::FileDialog::new().pick_folder(),
+                                RecordingFormat::Wav => rfd::FileDialog::new()
+                                    .add_filter("WAV audio", &["wav"])
+                                    .set_file_name(&screenshot::default_file_name("wav"))
+                                    .save_file(),
+                            };
+                            if path.is_some() {
+                                self.toggle_recording(path);
+                            }
+                        }
+                        egui::ComboBox::from_id_source("recording_format")
+                            .selected_text(self.debugger.recording_format.name())
+                            .show_ui(ui, |ui| {
+                                for format in RecordingFormat::ALL {
+                                    ui.selectable_value(&mut self.debugger.recording_format, format, format.name());
+                                }
+                            });
+                        if self.debugger.recording_format != RecordingFormat::Wav {
+                            ui.checkbox(&mut self.debugger.recording_audio, "With sound");
+                        }
+                    }
+                });
+                ui.separator();
+                if ui.button("Restore defaults").clicked() {
+                    self.debugger.restore_defaults();
+                    self.layout.windows.clear();
+                    ui.ctx().memory().reset_areas();
+                }
+                if self.emulator_stopped {
+                    ui.colored_label(Color32::LIGHT_RED, "The emulator has stopped, restart to carry on");
+                }
+                if let Some(message) = &self.status_message {
+                    ui.label(message);
+                }
             });
+        self.remember_window("controls", response);
 
-        Window::new("registers")
+        let response = self.window("registers")
             .show(ctx, |ui| {
-                let chip8 = self.chip8.lock().unwrap();
                 for i in 0..REGISTER_COUNT {
-                    let reg_val = chip8.registers[i];
+                    let reg_val = snapshot.registers[i];
                     ui.code(format!("V{:x}: {:>3} 0x{:02x}", i, reg_val, reg_val));
                 }
-                ui.code(format!("I:  0x{:04x}", chip8.ir));
+                ui.code(format!("I:  0x{:04x}", snapshot.ir));
+                let stack: Vec<String> = snapshot.stack.iter().map(|addr| format!("{:03x}", addr)).collect();
+                ui.code(format!("SP: {:>2}/{}", stack.len(), snapshot.stack_depth));
+                if !stack.is_empty() {
+                    ui.code(stack.join(" "));
+                }
+                match snapshot.key_wait {
+                    KeyWait::Idle => (),
+                    KeyWait::Press => { ui.label("Waiting for a key press"); },
+                    KeyWait::Release(key) => { ui.label(format!("Waiting for key {:X} to be released", key)); }
+                }
             });
+        self.remember_window("registers", response);
 
-        let game_window_size = Vec2 { x: SCREEN_WIDTH as f32 * self.scale, y: SCREEN_HEIGHT as f32 * self.scale };
-
-        Window::new("game_window")
-            .fixed_size(game_window_size)
+        let response = self.window("keypad")
             .show(ctx, |ui| {
-                let chip8 = self.chip8.lock().unwrap();
-                let frame_buffer = chip8.frame_buffer;
-                let (resp, pt) = ui.allocate_painter(game_window_size, Sense::hover());
+                let keys_tested = snapshot.keys_tested;
+                let keys_held = keyboard_keys | self.keypad_clicked;
+                let keypad_size = Vec2 { x: 4.0 * KEYPAD_KEY_SIZE, y: 4.0 * KEYPAD_KEY_SIZE };
+                let (resp, pt) = ui.allocate_painter(keypad_size, Sense::click_and_drag());
                 let off = resp.rect.left_top();
-                for y in 0..SCREEN_HEIGHT {
-                    for x in 0..(SCREEN_WIDTH/8) {
-                        let byte = frame_buffer[y * SCREEN_WIDTH/8 + x];
-                        for xi in 0..8 {
-                            let bit = (byte & (1 << (7-xi))) > 0;
-                            let rect = Rect { 
-                                min: Pos2 { x: off.x+(x*8+xi) as f32 * self.scale, y: off.y+y as f32 * self.scale },
-                                max: Pos2 { x: off.x+(x*8+xi+1) as f32 * self.scale, y: off.y+(y+1) as f32 * self.scale },
-                            };
-                            pt.rect_filled(rect, Rounding::none(), if bit { Color32::WHITE } else { Color32::BLACK });
+
+                self.keypad_clicked = 0;
+                if resp.is_pointer_button_down_on() {
+                    if let Some(pos) = resp.interact_pointer_pos() {
+                        let col = ((pos.x - off.x) / KEYPAD_KEY_SIZE).floor() as usize;
+                        let row = ((pos.y - off.y) / KEYPAD_KEY_SIZE).floor() as usize;
+                        if row < 4 && col < 4 {
+                            self.keypad_clicked = 1 << KEYPAD_LAYOUT[row][col];
+                        }
+                    }
+                }
+
+                for (row, keys) in KEYPAD_LAYOUT.iter().enumerate() {
+                    for (col, &key) in keys.iter().enumerate() {
+                        let rect = Rect {
+                            min: Pos2 { x: off.x + col as f32 * KEYPAD_KEY_SIZE, y: off.y + row as f32 * KEYPAD_KEY_SIZE },
+                            max: Pos2 { x: off.x + (col+1) as f32 * KEYPAD_KEY_SIZE, y: off.y + (row+1) as f32 * KEYPAD_KEY_SIZE },
+                        }.shrink(2.0);
+                        let held = (keys_held & (1 << key)) > 0;
+                        let tested = (keys_tested & (1 << key)) > 0;
+                        let (fill, text) = if held { (Color32::WHITE, Color32::BLACK) } else { (Color32::from_gray(40), Color32::WHITE) };
+                        pt.rect_filled(rect, Rounding::same(4.0), fill);
+                        if tested {
+                            pt.rect_stroke(rect, Rounding::same(4.0), Stroke::new(2.0, Color32::YELLOW));
                         }
+                        pt.text(rect.center(), Align2::CENTER_CENTER, format!("{:X}", key), FontId::monospace(KEYPAD_KEY_SIZE / 2.0), text);
                     }
                 }
             });
+        self.remember_window("keypad", response);
+
+        {
+            let mut input_lock = self.input_mutex.lock().unwrap();
+            *input_lock = keyboard_keys | self.keypad_clicked;
+        }
+
+        if let Some(fault) = &snapshot.fault {
+            self.show_fault(ctx, fault);
+        }
+        self.show_library(ctx);
+
+        self.update_display_texture(ctx);
+        let game_window_size = Vec2 { x: SCREEN_WIDTH as f32 * self.scale, y: SCREEN_HEIGHT as f32 * self.scale };
+
+        let response = self.window("game_window")
+            .default_size(game_window_size)
+            .resizable(true)
+            .show(ctx, |ui| {
+                let available = ui.available_size();
+                let size = self.debugger.scale_mode.display_size(available);
+                ui.centered_and_justified(|ui| {
+                    if let Some(texture) = &self.display_texture {
+                        ui.image(texture, size);
+                    }
+                });
+            });
+        self.remember_window("game_window", response);
 
+        if self.sent_settings.sync(&self.debugger, &self.debug_sender).is_err() {
+            self.emulator_stopped = true;
+        }
         ctx.request_repaint();
     }
+
+    fn on_close_event(&mut self) -> bool {
+        let settings = Settings {
+            debugger: self.overrides.remove(&self.debugger),
+            layout: self.layout.clone()
+        };
+        if let Err(e) = settings.save() {
+            eprintln!("Couldn't save settings: {}", e);
+        }
+        true
+    }
+}
+
+impl ChipGUI {
+    // Sends a command to the emulator thread, noting if it's no longer there.
+    fn send(&mut self, instruction: DebugInstructions) {
+        if self.debug_sender.send(instruction).is_err() {
+            self.emulator_stopped = true;
+        }
+    }
+
+    // Saves the current display with the active palette, reporting the result in the controls window.
+    fn save_screenshot(&mut self, path: &Path) {
+        let result = screenshot::save_screenshot(&self.snapshot.frame_buffer, self.debugger.active_palette(), self.debugger.screenshot_scale, path);
+        self.status_message = Some(match result {
+            Ok(()) => format!("Saved screenshot to {}", path.display()),
+            Err(e) => format!("Couldn't save screenshot: {}", e),
+        });
+    }
+
+    // Loads a ROM, switching to its platform's quirks if it's known.
+    fn load_rom(&mut self, path: &str, platform: Option<Platform>) {
+        self.debugger.opened_rom(path);
+        if let Some(platform) = platform {
+            self.debugger.platform = platform;
+            self.debugger.quirks = platform.quirks();
+        }
+        self.debugger.register_scroll = 0;
+        self.send(DebugInstructions::Reload(path.to_string()));
+    }
+
+    // Shows what went wrong and what can be done about it.
+    fn show_fault(&mut self, ctx: &Context, fault: &ChipFault) {
+        let response = self.window("error")
+            .show(ctx, |ui| {
+                ui.colored_label(Color32::LIGHT_RED, fault.to_string());
+                ui.code(format!("PC: 0x{:04x}  I: 0x{:04x}", fault.pc, fault.ir));
+                let stack: Vec<String> = fault.stack.iter().map(|addr| format!("{:03x}", addr)).collect();
+                ui.code(format!("Stack: [{}]", stack.join(" ")));
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(fault.opcode.is_some(), egui::Button::new("Continue as NOP")).clicked() {
+                        self.send(DebugInstructions::ContinueAsNop);
+                    }
+                    if ui.button("Reset").clicked() {
+                        self.send(DebugInstructions::Reset);
+                        self.debugger.register_scroll = 0;
+                    }
+                    if ui.button("Dump state").clicked() {
+                        let dialog = rfd::FileDialog::new()
+                            .add_filter("Text file", &["txt"])
+                            .set_file_name(&screenshot::default_file_name("txt"));
+                        if let Some(path) = dialog.save_file() {
+                            self.dump_fault(fault, &path);
+                        }
+                    }
+                });
+            });
+        self.remember_window("error", response);
+    }
+
+    // Writes a report of the fault, with a save state of the machine next to it.
+    fn dump_fault(&mut self, fault: &ChipFault, path: &Path) {
+        let state_path = path.with_extension("c8s");
+        let result = std::fs::write(path, fault.report()).and_then(|_| std::fs::write(&state_path, &fault.state));
+        self.status_message = Some(match result {
+            Ok(()) => format!("Saved state to {} and {}", path.display(), state_path.display()),
+            Err(e) => format!("Couldn't save state: {}", e),
+        });
+    }
+
+    // Loads a ROM dropped onto the window, and shows where to drop it while one is dragged over.
+    fn handle_dropped_files(&mut self, ctx: &Context) {
+        let (hovering, dropped) = {
+            let input = ctx.input();
+            (!input.raw.hovered_files.is_empty(), input.raw.dropped_files.iter().find_map(|file| file.path.clone()))
+        };
+        if hovering {
+            let painter = ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("drop_target")));
+            let screen = ctx.input().screen_rect();
+            painter.rect_filled(screen, Rounding::none(), Color32::from_black_alpha(180));
+            painter.text(screen.center(), Align2::CENTER_CENTER, "Drop a ROM to load it", FontId::proportional(24.0), Color32::WHITE);
+        }
+        if let Some(path) = dropped {
+            self.load_rom(&path.display().to_string(), library::platform_from_path(&path));
+        }
+    }
+
+    // Rescans the library folders in the background.
+    fn scan_library(&mut self) {
+        let dirs = self.debugger.library_dirs.clone();
+        let (send, recv) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = send.send(library::scan(&dirs));
+        });
+        self.library_scan = Some(recv);
+    }
+
+    fn show_library(&mut self, ctx: &Context) {
+        if let Some(Ok(entries)) = self.library_scan.as_ref().map(|recv| recv.try_recv()) {
+            self.library = entries;
+            self.library_scan = None;
+            self.thumbnails.clear();
+        }
+        let palette = self.debugger.active_palette();
+        if palette != self.thumbnail_palette {
+            self.thumbnails.clear();
+            self.thumbnail_palette = palette;
+        }
+        for entry in &self.library {
+            if let (Some(frame_buffer), false) = (&entry.thumbnail, self.thumbnails.contains_key(&entry.path)) {
+                let texture = ctx.load_texture(entry.path.display().to_string(), screenshot::render(frame_buffer, palette, 1), TextureFilter::Nearest);
+                self.thumbnails.insert(entry.path.clone(), texture);
+            }
+        }
+
+        let mut load = None;
+        let mut rescan = false;
+        let response = self.window("library")
+            .show(ctx, |ui| {
+                ui.collapsing("Recently played", |ui| {
+                    if self.debugger.recent_roms.is_empty() {
+                        ui.label("Nothing yet");
+                    }
+                    for path in &self.debugger.recent_roms {
+                        if ui.button(library::title_from_path(Path::new(path))).on_hover_text(path).clicked() {
+                            load = Some((path.clone(), None));
+                        }
+                    }
+                });
+
+                ui.collapsing("Folders", |ui| {
+                    let mut removed = None;
+                    for (i, dir) in self.debugger.library_dirs.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            if ui.small_button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                            ui.label(dir.display().to_string());
+                        });
+                    }
+                    if let Some(i) = removed {
+                        self.debugger.library_dirs.remove(i);
+                        rescan = true;
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Add folder").clicked() {
+                            if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                                if !self.debugger.library_dirs.contains(&dir) {
+                                    self.debugger.library_dirs.push(dir);
+                                    rescan = true;
+                                }
+                            }
+                        }
+                        if ui.button("Rescan").clicked() {
+                            rescan = true;
+                        }
+                    });
+                });
+
+                if self.library_scan.is_some() {
+                    ui.label("Scanning...");
+                } else if self.library.is_empty() {
+                    ui.label("No ROMs found. Add a folder with .ch8 files in it.");
+                }
+                let thumbnail_size = Vec2 { x: SCREEN_WIDTH as f32 * THUMBNAIL_SCALE, y: SCREEN_HEIGHT as f32 * THUMBNAIL_SCALE };
+                egui::ScrollArea::vertical().max_height(LIBRARY_HEIGHT).show(ui, |ui| {
+                    for entry in &self.library {
+                        ui.horizontal(|ui| {
+                            let clicked = match self.thumbnails.get(&entry.path) {
+                                Some(texture) => ui.add(egui::ImageButton::new(texture, thumbnail_size)).clicked(),
+                                None => ui.add_sized(thumbnail_size, egui::Button::new("?")).clicked(),
+                            };
+                            ui.vertical(|ui| {
+                                let title_clicked = ui.selectable_label(self.debugger.rom_path.as_deref() == entry.path.to_str(), &entry.title).clicked();
+                                ui.label(entry.platform.map_or("Unknown platform", |platform| platform.name()));
+                                if clicked || title_clicked {
+                                    load = Some((entry.path.display().to_string(), entry.platform));
+                                }
+                            });
+                        });
+                    }
+                });
+            });
+        self.remember_window("library", response);
+
+        if let Some((path, platform)) = load {
+            self.load_rom(&path, platform);
+        }
+        if rescan {
+            self.scan_library();
+        }
+    }
+
+    // A debugger window, opening where it was last time.
+    fn window(&self, title: &str) -> Window<'static> {
+        let window = Window::new(title);
+        match self.layout.windows.get(title) {
+            Some(pos) => window.default_pos(*pos),
+            None => window
+        }
+    }
+
+    fn remember_window<R>(&mut self, title: &str, response: Option<InnerResponse<R>>) {
+        if let Some(response) = response {
+            self.layout.windows.insert(title.to_string(), response.response.rect.min);
+        }
+    }
+
+    // Saves the machine state to a file, which can be loaded again or passed to --load-state.
+    fn save_state(&mut self, path: &Path) {
+        self.status_message = Some(match std::fs::write(path, &self.snapshot.state) {
+            Ok(()) => format!("Saved state to {}", path.display()),
+            Err(e) => format!("Couldn't save state: {}", e),
+        });
+    }
+
+    // The emulator thread owns the machine, so this asks it to load the state
+    // and check_state_load picks up the answer.
+    fn load_state(&mut self, path: &Path) {
+        let state = match std::fs::read(path) {
+            Ok(state) => state,
+            Err(e) => {
+                self.status_message = Some(format!("Couldn't load state: {}", e));
+                return;
+            }
+        };
+        let (reply, answer) = mpsc::channel();
+        self.send(DebugInstructions::LoadState(state, reply));
+        // if that didn't get through, the answer comes back as disconnected
+        self.state_load = Some((path.to_path_buf(), answer));
+        self.status_message = Some(format!("Loading state from {}", path.display()));
+    }
+
+    fn check_state_load(&mut self) {
+        let result = match &self.state_load {
+            Some((_, answer)) => match answer.try_recv() {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err("the emulator has stopped".to_string()),
+            },
+            None => return
+        };
+        let (path, _) = self.state_load.take().unwrap();
+        self.status_message = Some(match result {
+            Ok(()) => format!("Loaded state from {}", path.display()),
+            Err(e) => format!("Couldn't load state: {}", e),
+        });
+    }
+
+    // Starts recording to `path` (or a default path) or stops the current recording.
+    fn toggle_recording(&mut self, path: Option<PathBuf>) {
+        if let Some(settings) = self.debugger.recording.take() {
+            self.status_message = Some(format!("Saving recording to {}", settings.path.display()));
+            return;
+        }
+        let format = self.debugger.recording_format;
+        let path = path.unwrap_or_else(|| PathBuf::from(screenshot::default_file_name(match format {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::PngSequence => "",
+            RecordingFormat::Wav => "wav",
+        })));
+        self.status_message = Some(format!("Recording to {}", path.display()));
+        self.debugger.recording = Some(RecordingSettings {
+            path,
+            format,
+            scale: self.debugger.screenshot_scale,
+            palette: self.debugger.active_palette(),
+            audio: self.debugger.recording_audio,
+            tone: self.debugger.tone
+        });
+    }
+
+    // Redraws the display texture if the display or the way it's drawn has changed.
+    fn update_display_texture(&mut self, ctx: &Context) {
+        let snapshot = self.snapshot.clone();
+        let key = DisplayKey {
+            palette: self.debugger.active_palette(),
+            render_mode: self.debugger.render_mode,
+            // raw frames only change when drawn to, the others also change over time
+            frame_count: if self.debugger.render_mode == RenderMode::Raw { 0 } else { snapshot.frame_count },
+            display_version: snapshot.display_version
+        };
+        if self.display_texture.is_some() && self.display_key.as_ref() == Some(&key) {
+            return;
+        }
+
+        let colors = key.palette.colors();
+        let mut image = ColorImage::new([SCREEN_WIDTH, SCREEN_HEIGHT], colors[0]);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let intensity = match key.render_mode {
+                    // the machine as it is, so stepping shows every draw
+                    RenderMode::Raw => get_pixel(&snapshot.frame_buffer, x, y) as u8 as f32,
+                    mode => snapshot.phosphor.intensity(mode, x, y)
+                };
+                image.pixels[y * SCREEN_WIDTH + x] = palette::blend(colors[0], colors[1], intensity);
+            }
+        }
+
+        match &mut self.display_texture {
+            Some(texture) => texture.set(image, TextureFilter::Nearest),
+            None => self.display_texture = Some(ctx.load_texture("display", image, TextureFilter::Nearest)),
+        }
+        self.display_key = Some(key);
+    }
+}
+
+// Combo box for choosing a palette, with colour pickers for custom palettes.
+fn palette_picker(ui: &mut egui::Ui, palette: &mut Palette) {
+    egui::ComboBox::from_label("Palette")
+        .selected_text(palette.name())
+        .show_ui(ui, |ui| {
+            for preset in Palette::PRESETS {
+                ui.selectable_value(palette, preset, preset.name());
+            }
+            let custom = match palette {
+                Palette::Custom(_) => *palette,
+                _ => Palette::Custom(palette.colors())
+            };
+            ui.selectable_value(palette, custom, custom.name());
+        });
+    if let Palette::Custom(colors) = palette {
+        ui.horizontal(|ui| {
+            for color in colors.iter_mut() {
+                ui.color_edit_button_srgba(color);
+            }
+        });
+    }
 }
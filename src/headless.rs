@@ -0,0 +1,60 @@
+// Running the emulator without a frontend, as fast as possible.
+
+use std::{error::Error, path::Path};
+
+use rand_pcg::Pcg32;
+use crate::audio::{self, AudioBackend, CaptureAudio};
+use crate::beep::ToneSettings;
+use crate::chip8::{Chip8, REGISTER_COUNT};
+use crate::tui;
+
+// Roughly how many instructions the frontends run per frame at 1x speed.
+pub const DEFAULT_TICKS_PER_FRAME: u32 = 8;
+// Fixed seed, so headless runs always come out the same.
+pub const DEFAULT_SEED: u64 = 0xcafef00dd15ea5e5;
+
+// Makes a machine with the ROM loaded and a fixed random seed.
+pub fn new_machine(rom: &[u8], seed: u64) -> Result<Chip8, Box<dyn Error>> {
+    let mut chip8 = Chip8::new(Pcg32::new(seed, 0xa02bdbf7bb3c0a7));
+    chip8.load(rom)?;
+    Ok(chip8)
+}
+
+// Runs whole frames, feeding the sound of each one to `audio`.
+pub fn run_frames(chip8: &mut Chip8, frames: u32, ticks_per_frame: u32, key_input: u16, audio: &mut dyn AudioBackend) -> Result<(), Box<dyn Error>> {
+    for _ in 0..frames {
+        for _ in 0..ticks_per_frame {
+            let ticked = chip8.tick(key_input);
+            for line in chip8.take_trace() {
+                println!("{}", line);
+            }
+            ticked?;
+        }
+        chip8.frame();
+        audio.push_frame(chip8.sound_playing, 1.0)?;
+    }
+    Ok(())
+}
+
+// Prints the display and registers, for looking at the end of a headless run.
+pub fn print_state(chip8: &Chip8) {
+    for line in tui::display_lines(&chip8.frame_buffer) {
+        println!("{}", line.trim_end());
+    }
+    for i in 0..REGISTER_COUNT / 2 {
+        let high = i + REGISTER_COUNT / 2;
+        println!("V{:x}: 0x{:02x}  V{:x}: 0x{:02x}", i, chip8.registers[i], high, chip8.registers[high]);
+    }
+    println!("I:  0x{:04x}", chip8.ir);
+    println!("PC: 0x{:04x}", chip8.pc);
+    println!("DT: {}", chip8.dt);
+    println!("Frames: {}", chip8.frame_count);
+}
+
+// Runs a machine with no input and saves the sound it makes to a WAV file.
+pub fn export_sound(chip8: &mut Chip8, frames: u32, ticks_per_frame: u32, tone: ToneSettings, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut capture = CaptureAudio::default();
+    run_frames(chip8, frames, ticks_per_frame, 0, &mut capture)?;
+    audio::export_wav(&capture.frames, tone, path)?;
+    Ok(())
+}
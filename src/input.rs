@@ -2,6 +2,14 @@ use std::{sync::{Mutex, Arc}, collections::HashSet};
 
 use egui::Key;
 
+// Layout of the hex keypad as it appears on the original hardware.
+pub const KEYPAD_LAYOUT: [[u8; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
 pub struct InputDriver {
     pub keys: Arc<Mutex<u16>>,
 }
@@ -38,4 +46,27 @@ impl InputDriver {
         }
         out
     }
+
+    // Keypad key for a character typed in a terminal, using the same layout as convert_keys.
+    pub fn convert_char(c: char) -> Option<u8> {
+        match c.to_ascii_lowercase() {
+            '1' => Some(0x1),
+            '2' => Some(0x2),
+            '3' => Some(0x3),
+            '4' => Some(0xC),
+            'q' => Some(0x4),
+            'w' => Some(0x5),
+            'e' => Some(0x6),
+            'r' => Some(0xD),
+            'a' => Some(0x7),
+            's' => Some(0x8),
+            'd' => Some(0x9),
+            'f' => Some(0xE),
+            'z' => Some(0xA),
+            'x' => Some(0x0),
+            'c' => Some(0xB),
+            'v' => Some(0xF),
+            _   => None
+        }
+    }
 }
@@ -0,0 +1,173 @@
+// Decoded instructions, shared by the interpreter and the disassembler so
+// there's one place that knows what each opcode means.
+
+use std::fmt;
+
+use crate::chip8::{get_nnn, Platform};
+
+// Registers are numbered 0 to F, addresses are 12 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Cls,                // 00E0 clear display
+    Ret,                // 00EE return from subroutine
+    Jp(u16),            // 1nnn jump
+    Call(u16),          // 2nnn call subroutine
+    Se(u8, u8),         // 3xkk skip if Vx == kk
+    Sne(u8, u8),        // 4xkk skip if Vx != kk
+    SeReg(u8, u8),      // 5xy0 skip if Vx == Vy
+    Ld(u8, u8),         // 6xkk Vx = kk
+    Add(u8, u8),        // 7xkk Vx += kk, no carry
+    LdReg(u8, u8),      // 8xy0 Vx = Vy
+    Or(u8, u8),         // 8xy1
+    And(u8, u8),        // 8xy2
+    Xor(u8, u8),        // 8xy3
+    AddReg(u8, u8),     // 8xy4 VF = carry
+    SubReg(u8, u8),     // 8xy5 Vx -= Vy, VF = no borrow
+    Shr(u8, u8),        // 8xy6 shifts Vy into Vx with the shift quirk, otherwise Vx
+    SubnReg(u8, u8),    // 8xy7 Vx = Vy - Vx, VF = no borrow
+    Shl(u8, u8),        // 8xyE
+    SneReg(u8, u8),     // 9xy0 skip if Vx != Vy
+    LdI(u16),           // Annn I = nnn
+    JpV0(u16),          // Bnnn jump to V0 + nnn
+    Rnd(u8, u8),        // Cxkk Vx = random & kk
+    Drw(u8, u8, u8),    // Dxyn draw n rows at (Vx, Vy)
+    Skp(u8),            // Ex9E skip if key Vx is down
+    Sknp(u8),           // ExA1 skip if key Vx is up
+    LdVxDt(u8),         // Fx07 Vx = delay timer
+    KeyWait(u8),        // Fx0A wait for a key and put it in Vx
+    LdDtVx(u8),         // Fx15 delay timer = Vx
+    LdStVx(u8),         // Fx18 sound timer = Vx
+    AddIVx(u8),         // Fx1E I += Vx
+    LdFVx(u8),          // Fx29 I = font digit Vx
+    LdBVx(u8),          // Fx33 store Vx in decimal at I
+    LdIVx(u8),          // Fx55 store V0 to Vx at I
+    LdVxI(u8)           // Fx65 load V0 to Vx from I
+}
+
+// Decodes an opcode, or returns None if it isn't an instruction. Every
+// platform has the same instructions for now; SUPER-CHIP's extra ones will
+// be decided here when they're added.
+pub fn decode(opcode: u16, _platform: Platform) -> Option<Instruction> {
+    let [b1, b2] = opcode.to_be_bytes();
+    let x = b1 & 0x0F;
+    let y = b2 >> 4;
+    let n = b2 & 0x0F;
+    let nnn = get_nnn(b1, b2);
+    let instruction = match b1 >> 4 {
+        0x0 => match nnn {
+            0x0E0 => Instruction::Cls,
+            0x0EE => Instruction::Ret,
+            _ => return None
+        },
+        0x1 => Instruction::Jp(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::Se(x, b2),
+        0x4 => Instruction::Sne(x, b2),
+        0x5 if n == 0 => Instruction::SeReg(x, y),
+        0x6 => Instruction::Ld(x, b2),
+        0x7 => Instruction::Add(x, b2),
+        0x8 => match n {
+            0x0 => Instruction::LdReg(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::SubReg(x, y),
+            0x6 => Instruction::Shr(x, y),
+            0x7 => Instruction::SubnReg(x, y),
+            0xE => Instruction::Shl(x, y),
+            _ => return None
+        },
+        0x9 if n == 0 => Instruction::SneReg(x, y),
+        0xA => Instruction::LdI(nnn),
+        0xB => Instruction::JpV0(nnn),
+        0xC => Instruction::Rnd(x, b2),
+        0xD => Instruction::Drw(x, y, n),
+        0xE => match b2 {
+            0x9E => Instruction::Skp(x),
+            0xA1 => Instruction::Sknp(x),
+            _ => return None
+        },
+        0xF => match b2 {
+            0x07 => Instruction::LdVxDt(x),
+            0x0A => Instruction::KeyWait(x),
+            0x15 => Instruction::LdDtVx(x),
+            0x18 => Instruction::LdStVx(x),
+            0x1E => Instruction::AddIVx(x),
+            0x29 => Instruction::LdFVx(x),
+            0x33 => Instruction::LdBVx(x),
+            0x55 => Instruction::LdIVx(x),
+            0x65 => Instruction::LdVxI(x),
+            _ => return None
+        },
+        _ => return None
+    };
+    Some(instruction)
+}
+
+// Assembly for the instruction. Shifts show Vy in angle brackets, since it's
+// only used with the shift quirk.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jp(addr) => write!(f, "JP   0x{:03x}", addr),
+            Instruction::Call(addr) => write!(f, "CALL 0x{:03x}", addr),
+            Instruction::Se(x, val) => write!(f, "SE   V{:x},  0x{:02x}", x, val),
+            Instruction::Sne(x, val) => write!(f, "SNE  V{:x},  0x{:02x}", x, val),
+            Instruction::SeReg(x, y) => write!(f, "SE   V{:x},  V{:x}", x, y),
+            Instruction::Ld(x, val) => write!(f, "LD   V{:x},  0x{:02x}", x, val),
+            Instruction::Add(x, val) => write!(f, "ADD  V{:x},  0x{:02x}", x, val),
+            Instruction::LdReg(x, y) => write!(f, "LD   V{:x},  V{:x}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR   V{:x},  V{:x}", x, y),
+            Instruction::And(x, y) => write!(f, "AND  V{:x},  V{:x}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR  V{:x},  V{:x}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD  V{:x},  V{:x}", x, y),
+            Instruction::SubReg(x, y) => write!(f, "SUB  V{:x},  V{:x}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR  V{:x},  <V{:x}>", x, y),
+            Instruction::SubnReg(x, y) => write!(f, "SUBN V{:x},  V{:x}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL  V{:x},  <V{:x}>", x, y),
+            Instruction::SneReg(x, y) => write!(f, "SNE  V{:x},  V{:x}", x, y),
+            Instruction::LdI(addr) => write!(f, "LD   I,   0x{:03x}", addr),
+            Instruction::JpV0(addr) => write!(f, "JP   V0,  0x{:03x}", addr),
+            Instruction::Rnd(x, mask) => write!(f, "RND  V{:x},  0x{:02x}", x, mask),
+            Instruction::Drw(x, y, rows) => write!(f, "DRW  V{:x},  V{:x},  0x{:x}", x, y, rows),
+            Instruction::Skp(x) => write!(f, "SKP  V{:x}", x),
+            Instruction::Sknp(x) => write!(f, "SKNP V{:x}", x),
+            Instruction::LdVxDt(x) => write!(f, "LD   V{:x},  DT", x),
+            Instruction::KeyWait(x) => write!(f, "LD   V{:x},  K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD   DT,  V{:x}", x),
+            Instruction::LdStVx(x) => write!(f, "LD   ST,  V{:x}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD  I,   V{:x}", x),
+            Instruction::LdFVx(x) => write!(f, "LD   F,   V{:x}", x),
+            Instruction::LdBVx(x) => write!(f, "LD   B,   V{:x}", x),
+            Instruction::LdIVx(x) => write!(f, "LD   [I], V{:x}", x),
+            Instruction::LdVxI(x) => write!(f, "LD   V{:x},  [I]", x)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_operands() {
+        let platform = Platform::default();
+        assert_eq!(decode(0x2ABC, platform), Some(Instruction::Call(0xABC)));
+        assert_eq!(decode(0x3A12, platform), Some(Instruction::Se(0xA, 0x12)));
+        assert_eq!(decode(0x8AB6, platform), Some(Instruction::Shr(0xA, 0xB)));
+        assert_eq!(decode(0xD123, platform), Some(Instruction::Drw(1, 2, 3)));
+        assert_eq!(decode(0xF965, platform), Some(Instruction::LdVxI(9)));
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        for opcode in [0x0000, 0x07E0, 0x5121, 0x8128, 0x912F, 0xE19F, 0xF100] {
+            for platform in Platform::ALL {
+                assert_eq!(decode(opcode, platform), None, "{:04x}", opcode);
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Finding ROMs in the user's library folders.
+//
+// A folder can have a library.toml next to its ROMs giving each one a title
+// and a platform, keyed by file name:
+//
+//     ["pong.ch8"]
+//     title = "Pong"
+//     platform = "vip"
+//
+// ROMs without an entry are named after their file.
+
+use std::{fs, path::{Path, PathBuf}};
+
+use toml::value::{Table, Value};
+use crate::audio::NullAudio;
+use crate::chip8::{FrameBuffer, Platform};
+use crate::headless::{self, DEFAULT_SEED, DEFAULT_TICKS_PER_FRAME};
+
+const METADATA_FILE: &str = "library.toml";
+const ROM_EXTENSIONS: [&str; 3] = ["ch8", "c8", "sc8"];
+// How long a ROM runs for before its thumbnail is taken, about two seconds
+const THUMBNAIL_FRAMES: u32 = 120;
+
+#[derive(Clone)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    pub title: String,
+    pub platform: Option<Platform>,     // None if unknown
+    pub thumbnail: Option<FrameBuffer>
+}
+
+// Lists the ROMs in each folder, sorted by title. This runs every ROM for
+// a moment to take its thumbnail, so it's best done off the GUI thread.
+pub fn scan(dirs: &[PathBuf]) -> Vec<RomEntry> {
+    let mut entries = vec![];
+    for dir in dirs {
+        let read_dir = match fs::read_dir(dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                eprintln!("Couldn't read ROM folder {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        let metadata = read_metadata(dir);
+        for path in read_dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if !is_rom(&path) {
+                continue;
+            }
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let info = metadata.get(&file_name).and_then(Value::as_table);
+            let title = info.and_then(|info| info.get("title")).and_then(Value::as_str)
+                .map(String::from)
+                .unwrap_or_else(|| title_from_path(&path));
+            let platform = info.and_then(|info| info.get("platform")).and_then(Value::as_str)
+                .and_then(|platform| platform.parse().ok())
+                .or_else(|| platform_from_path(&path));
+            let thumbnail = fs::read(&path).ok().and_then(|rom| thumbnail(&rom, platform.unwrap_or_default()));
+            entries.push(RomEntry { path, title, platform, thumbnail });
+        }
+    }
+    entries.sort_by_key(|entry| entry.title.to_lowercase());
+    entries
+}
+
+// Runs a ROM for a couple of seconds with no input and returns the display.
+// ROMs that crash still get a thumbnail of whatever they drew before that.
+pub fn thumbnail(rom: &[u8], platform: Platform) -> Option<FrameBuffer> {
+    let mut chip8 = headless::new_machine(rom, DEFAULT_SEED).ok()?;
+    chip8.quirks_mode = platform.quirks();
+    chip8.platform = platform;
+    let _ = headless::run_frames(&mut chip8, THUMBNAIL_FRAMES, DEFAULT_TICKS_PER_FRAME, 0, &mut NullAudio);
+    Some(chip8.frame_buffer)
+}
+
+pub fn is_rom(path: &Path) -> bool {
+    path.is_file() && path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+// "space_invaders.ch8" becomes "space invaders".
+pub fn title_from_path(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().replace('_', " "))
+        .unwrap_or_default()
+}
+
+// Guesses the platform from the file extension.
+pub fn platform_from_path(path: &Path) -> Option<Platform> {
+    match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "sc8" => Some(Platform::Schip),
+        _ => None
+    }
+}
+
+fn read_metadata(dir: &Path) -> Table {
+    let path = dir.join(METADATA_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return Table::new(),
+    };
+    match text.parse::<Value>() {
+        Ok(Value::Table(table)) => table,
+        Ok(_) => Table::new(),
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", path.display(), e);
+            Table::new()
+        }
+    }
+}
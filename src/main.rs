@@ -1,11 +1,23 @@
-use std::{env, time::{Duration, Instant}, sync};
+use std::{process, time::{Duration, Instant}, sync};
+use std::error::Error;
+use std::fs::File;
+use std::io::{LineWriter, Write};
 use std::sync::{Arc, Mutex};
 
-use chip8::Chip8;
-use debugger::{DebuggerState, DebugInstructions};
+use audio::{AudioBackend, NullAudio};
+use beep::ToneSettings;
+use chip8::{Chip8, ChipFault, INSTRUCTION_SIZE};
+use clap::Parser;
+use cli::{Args, Overrides};
+use debugger::{DebugInstructions, EmulatorSettings, EmulatorStatus};
 use gui::ChipGUI;
 use input::InputDriver;
+use phosphor::Phosphor;
 use rand::{RngCore, thread_rng};
+use recorder::Recorder;
+use settings::Settings;
+use snapshot::Snapshot;
+use tui::ChipTUI;
 
 mod chip8;
 mod loader;
@@ -13,130 +25,248 @@ mod gui;
 mod input;
 mod hexes;
 mod translator;
+mod instruction;
 mod beep;
 mod debugger;
+mod palette;
+mod phosphor;
+mod screenshot;
+mod recorder;
+mod wav;
+mod tui;
+mod audio;
+mod headless;
+mod cli;
+mod settings;
+mod library;
+mod watcher;
+mod snapshot;
+#[cfg(test)]
+mod conformance;
+#[cfg(test)]
+mod fuzz;
 
 const SLEEP_TIME: Duration = Duration::from_millis(2);
 const FRAME_DURATION: f32 = 1.0/60.0;
+// How often the frontends get a new snapshot of the machine while it runs
+const LOG_FILE: &str = "chip8.log";
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let mut file = match args.get(1) {
-        Some(str) => loader::get_file_bytes(str)?,
+const SNAPSHOT_TIME: Duration = Duration::from_millis(8);
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let rom_path = args.rom.as_ref().map(|path| path.display().to_string());
+    let mut file = match &rom_path {
+        Some(path) => loader::get_file_bytes(path)
+            .map_err(|e| format!("Couldn't read ROM file {}: {}", path, e))?,
         None => vec![],
     };
+    let state = match &args.load_state {
+        Some(path) => Some(std::fs::read(path)
+            .map_err(|e| format!("Couldn't read save state {}: {}", path.display(), e))?),
+        None => None,
+    };
+
+    // headless runs ignore saved settings and use a fixed seed, so they always come out the same
+    let headless = args.headless || args.export_sound.is_some();
+    let settings = if headless { Settings::default() } else { Settings::load() };
+    let seed = if headless { Some(args.seed.unwrap_or(headless::DEFAULT_SEED)) } else { args.seed };
+    let mut debugger_state = settings.debugger.clone();
+    args.apply(&mut debugger_state);
+    let overrides = Overrides { saved: settings.debugger.clone(), overridden: debugger_state.clone() };
+    match &rom_path {
+        Some(path) if !file.is_empty() => debugger_state.opened_rom(path),
+        _ => debugger_state.paused = true,
+    }
+
+    let mut chip8 = Chip8::new(new_rng(seed));
+    chip8.quirks_mode = debugger_state.quirks;
+    chip8.platform = debugger_state.platform;
+    chip8.trace = args.trace;
+    chip8.load(&file)?;
+    if let (Some(state), Some(path)) = (&state, &args.load_state) {
+        chip8.load_state(state)
+            .map_err(|e| format!("Couldn't load save state {}: {}", path.display(), e))?;
+    }
+
+    if let Some(path) = &args.export_sound {
+        return headless::export_sound(&mut chip8, args.frames, debugger_state.ticks_per_frame, debugger_state.tone, path);
+    }
+
+    if args.headless {
+        headless::run_frames(&mut chip8, args.frames, debugger_state.ticks_per_frame, 0, &mut NullAudio)?;
+        headless::print_state(&chip8);
+        return Ok(());
+    }
 
     let input_driver = InputDriver::new();
     let driver_keys_clone = input_driver.keys.clone();
     let driver_keys_clone_2 = input_driver.keys.clone();
 
-    let rand_seed = rand::thread_rng().next_u64();
-    let chip_rand = rand_pcg::Pcg32::new(rand_seed, 0xa02bdbf7bb3c0a7);
-
-    let chip8arc = Arc::new(Mutex::new(Chip8::new(chip_rand)));
-    let chip8clone = chip8arc.clone();
-    let chip8_gui_clone = chip8arc.clone();
+    let (mut snapshot_sender, snapshots) = snapshot::channel(Snapshot::new(&chip8, Phosphor::new(&chip8.frame_buffer), 0, None));
 
-    let debugger = Arc::new(Mutex::new(DebuggerState::default()));
-    let debugger_chip8 = debugger.clone();
+    let mut settings_chip8 = EmulatorSettings::new(&debugger_state);
 
     let (debug_send, debug_recv) = sync::mpsc::channel::<DebugInstructions>();
 
-    if !file.is_empty() {
-        let mut w = chip8arc.lock().unwrap();
-        w.load(&file)?;
-    } else {
-        let mut debug_writer = debugger.lock().unwrap();
-        debug_writer.paused = true;
-    }
+    let status = Arc::new(Mutex::new(EmulatorStatus::default()));
+    let status_chip8 = status.clone();
 
+    let log = if args.tui { Log::File(Arc::new(Mutex::new(None))) } else { Log::Terminal };
+    let log_chip8 = log.clone();
+
+    let audio_output = args.audio.clone();
     std::thread::spawn(move || {
+        let log = log_chip8;
+        let mut chip8 = chip8;
+        let mut fault: Option<ChipFault> = None;    // nothing runs until this has been dealt with
+        // frame by frame display history for the frontends, which only see the machine now and then
+        let mut phosphor = Phosphor::new(&chip8.frame_buffer);
+        let mut display_version = 0;
+        let mut changed = true;     // whether there's anything new for the frontends
+        let mut last_snapshot = Instant::now();
+        let mut was_paused = false;
         let last_frame = Instant::now();
         let mut last_checked: i64 = 0;
-        let mut last_key_input: u16 = 0;
-        let beep = beep::Beep::new().unwrap();
+        let (mut audio, audio_error) = audio::open(&audio_output);
+        {
+            let mut status = status_chip8.lock().unwrap();
+            status.audio_backend = audio.name();
+            status.audio_error = audio_error;
+        }
+        let mut recorder: Option<Recorder> = None;
+        let mut tone = ToneSettings::default();
 
         loop {
             let clock_start = Instant::now();
+            let key_input = *driver_keys_clone.lock().unwrap();
 
-            let is_paused = {
-                let dbg = debugger_chip8.lock().unwrap();
-                dbg.paused
+            let (spd, ticks_per_frame) = (settings_chip8.run_speed, settings_chip8.ticks_per_frame.max(1));
+            let faulted = fault.is_some();
+            let is_paused = settings_chip8.paused || faulted;
+            // show exactly where it stopped
+            changed |= is_paused != was_paused;
+            was_paused = is_paused;
+
+            if settings_chip8.tone != tone {
+                tone = settings_chip8.tone;
+                if let Err(e) = audio.set_tone(tone) {
+                    audio_failed(&mut audio, &status_chip8, e);
+                }
+            }
+
+            match (recorder.is_some(), &settings_chip8.recording) {
+                (false, Some(settings)) => recorder = Some(Recorder::new(settings.clone())),
+                (true, None) => {
+                    let finished = recorder.take().unwrap();
+                    let log = log.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = finished.finish() {
+                            log.error(&format!("Couldn't save recording: {}", e));
+                        }
+                    });
+                },
+                _ => ()
+            }
+
+            let instruction = debug_recv.try_recv();
+            changed |= instruction.is_ok();
+            match instruction {
+                // stepping only makes sense while paused
+                Ok(DebugInstructions::Step) if is_paused && !faulted => {
+                    if let Err(new_fault) = tick(&mut chip8, key_input, &log) {
+                        machine_faulted(new_fault, &mut fault, &mut settings_chip8, &log);
+                    }
+                },
+                Ok(DebugInstructions::Frame) if is_paused && !faulted => {
+                    run_frame(&mut chip8, &mut audio, 1.0, &mut recorder, &mut phosphor, settings_chip8.phosphor_decay, &status_chip8);
+                },
+                // there's no instruction to skip if the program counter is out of bounds
+                Ok(DebugInstructions::ContinueAsNop) if fault.as_ref().is_some_and(|fault| fault.opcode.is_some()) => {
+                    fault = None;
+                    chip8.pc += INSTRUCTION_SIZE;
+                },
+                Ok(DebugInstructions::Reset) => {
+                    fault = None;
+                    reset_machine(&mut chip8, seed);
+                    chip8.load(&file).unwrap();
+                    phosphor = Phosphor::new(&chip8.frame_buffer);
+                },
+                Ok(DebugInstructions::Reload(path)) => {
+                    fault = None;
+                    let loaded = loader::get_file_bytes(&path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|bytes| {
+                            reset_machine(&mut chip8, seed);
+                            chip8.load(&bytes).map(|_| bytes).map_err(|e| e.to_string())
+                        });
+                    phosphor = Phosphor::new(&chip8.frame_buffer);
+                    match loaded {
+                        Ok(bytes) => file = bytes,
+                        Err(e) => log.error(&format!("Couldn't load ROM file {}: {}", path, e)),
+                    }
+                },
+                Ok(DebugInstructions::SetPaused(paused)) => settings_chip8.paused = paused,
+                Ok(DebugInstructions::SetSpeed(run_speed, ticks_per_frame)) => {
+                    settings_chip8.run_speed = run_speed;
+                    settings_chip8.ticks_per_frame = ticks_per_frame;
+                },
+                Ok(DebugInstructions::SetQuirks(platform, quirks)) => {
+                    chip8.platform = platform;
+                    chip8.quirks_mode = quirks;
+                },
+                Ok(DebugInstructions::SetTone(tone)) => settings_chip8.tone = tone,
+                Ok(DebugInstructions::SetRecording(recording)) => settings_chip8.recording = recording,
+                Ok(DebugInstructions::SetPhosphorDecay(decay)) => settings_chip8.phosphor_decay = decay,
+                Ok(DebugInstructions::LoadState(state, reply)) => {
+                    let _ = reply.send(chip8.load_state(&state));
+                    phosphor = Phosphor::new(&chip8.frame_buffer);
+                },
+                Err(sync::mpsc::TryRecvError::Disconnected) => {
+                    log.error("Error: disconnected");
+                    return;
+                },
+                _ => ()
             };
 
             if !is_paused {
-                let mut chip8 = chip8clone.lock().unwrap();
-                let time_mult = {
-                    let spd = debugger_chip8.lock().unwrap().run_speed;
-                    (last_frame.elapsed().as_secs_f32() / FRAME_DURATION * spd).floor() as i64
-                };
+                let time_mult = (last_frame.elapsed().as_secs_f32() / FRAME_DURATION * spd).floor() as i64;
 
                 if time_mult != last_checked {
                     last_checked = time_mult;
-                    chip8.frame();
-                    if chip8.sound_playing {
-                        beep.play().unwrap();
-                    } else {
-                        beep.pause().unwrap();
-                    }
+                    run_frame(&mut chip8, &mut audio, spd, &mut recorder, &mut phosphor, settings_chip8.phosphor_decay, &status_chip8);
                 }
 
-                {
-                    let key_input = driver_keys_clone.lock().unwrap();
-                    let key_press: u16 = last_key_input & !*key_input;
-                    if key_press > 0 {
-                        chip8.keypad_press(key_press);
-                    }
-                    last_key_input = *key_input;
+                if let Err(new_fault) = tick(&mut chip8, key_input, &log) {
+                    machine_faulted(new_fault, &mut fault, &mut settings_chip8, &log);
+                    changed = true;
                 }
+            }
 
-                if let Err(e) = chip8.tick(last_key_input) {
-                    println!("{}", e);
-                    return;
-                }
+            if chip8.display_changed {
+                chip8.display_changed = false;
+                display_version += 1;
+            }
+            if changed || (!is_paused && last_snapshot.elapsed() >= SNAPSHOT_TIME) {
+                snapshot_sender.publish(Snapshot::new(&chip8, phosphor.clone(), display_version, fault.clone()));
+                last_snapshot = Instant::now();
+                changed = false;
             } else {
-                match debug_recv.try_recv() {
-                    Ok(DebugInstructions::Step) => {
-                        let mut chip8 = chip8clone.lock().unwrap();
-                        if let Err(e) = chip8.tick(last_key_input) {
-                            println!("{}", e);
-                            return;
-                        }
-                    },
-                    Ok(DebugInstructions::Frame) => {
-                        let mut chip8 = chip8clone.lock().unwrap();
-                        chip8.frame();
-                        if chip8.sound_playing {
-                            beep.play().unwrap();
-                        } else {
-                            beep.pause().unwrap();
-                        }
-                    },
-                    Ok(DebugInstructions::Reset) => {
-                        let mut chip8 = chip8clone.lock().unwrap();
-                        *chip8 = Chip8::new(rand_pcg::Pcg32::new(thread_rng().next_u64(), 0xa02bdbf7bb3c0a7));
-                        chip8.load(&file).unwrap();
-                    },
-                    Ok(DebugInstructions::Reload(path)) => {
-                        let mut chip8 = chip8clone.lock().unwrap();
-                        *chip8 = Chip8::new(rand_pcg::Pcg32::new(thread_rng().next_u64(), 0xa02bdbf7bb3c0a7));
-                        file = loader::get_file_bytes(&path).unwrap();
-                        chip8.load(&file).unwrap();
-                    },
-                    Err(sync::mpsc::TryRecvError::Disconnected) => {
-                        eprintln!("Error: disconnected");
-                        return;
-                    },
-                    Err(_) => ()
-                };
+                snapshot_sender.flush();
             }
 
             let clock_total = clock_start.elapsed();
             let sleep_duration = if is_paused {
                 SLEEP_TIME.saturating_sub(clock_total)
             } else {
-                let spd = debugger_chip8.lock().unwrap().run_speed;
-                SLEEP_TIME.div_f32(spd).saturating_sub(clock_total)
+                Duration::from_secs_f32(FRAME_DURATION / ticks_per_frame as f32 / spd).saturating_sub(clock_total)
             };
 
             if !sleep_duration.is_zero() {
@@ -145,7 +275,122 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
 
-    eframe::run_native("Chip8", eframe::NativeOptions::default(), Box::new(|cc| Box::new(ChipGUI::new(cc, 8.0, driver_keys_clone_2, chip8_gui_clone, debugger, debug_send))));
+    let scale = args.scale;
+    if args.tui {
+        let debugger = ChipTUI::new(driver_keys_clone_2, snapshots, debugger_state, debug_send, status).run()?;
+        if log.written() {
+            eprintln!("Messages from the emulator were written to {}", LOG_FILE);
+        }
+        // the terminal has no window layout, so keep whatever the GUI saved last
+        let mut settings = Settings::load();
+        settings.debugger = overrides.remove(&debugger);
+        if let Err(e) = settings.save() {
+            eprintln!("Couldn't save settings: {}", e);
+        }
+    } else {
+        let options = eframe::NativeOptions {
+            initial_window_pos: settings.layout.window_pos,
+            initial_window_size: settings.layout.window_size,
+            ..eframe::NativeOptions::default()
+        };
+        let layout = settings.layout;
+        eframe::run_native("Chip8", options, Box::new(move |cc| Box::new(ChipGUI::new(cc, scale, layout, overrides, driver_keys_clone_2, snapshots, debugger_state, debug_send, status))));
+    }
 
     Ok(())
 }
+
+// Where the emulator thread reports errors and traces instructions. The terminal
+// frontend draws over the whole terminal, so with it they go to LOG_FILE instead.
+#[derive(Clone)]
+enum Log {
+    Terminal,
+    File(Arc<Mutex<Option<LineWriter<File>>>>)    // opened on the first message
+}
+
+impl Log {
+    fn error(&self, message: &str) {
+        match self {
+            Log::Terminal => eprintln!("{}", message),
+            Log::File(_) => self.write_file(message)
+        }
+    }
+
+    fn trace(&self, line: &str) {
+        match self {
+            Log::Terminal => println!("{}", line),
+            Log::File(_) => self.write_file(line)
+        }
+    }
+
+    // Whether anything has gone to the log file, so the user can be told to look there.
+    fn written(&self) -> bool {
+        match self {
+            Log::Terminal => false,
+            Log::File(file) => file.lock().unwrap().is_some()
+        }
+    }
+
+    fn write_file(&self, line: &str) {
+        if let Log::File(file) = self {
+            let mut file = file.lock().unwrap();
+            if file.is_none() {
+                // nowhere else to say it without messing up the screen, so give up quietly
+                *file = File::create(LOG_FILE).ok().map(LineWriter::new);
+            }
+            if let Some(file) = file.as_mut() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+}
+
+// Ticks the timers, then feeds the beeper, the recording and the display history to match.
+fn run_frame(chip8: &mut Chip8, audio: &mut Box<dyn AudioBackend>, run_speed: f32, recorder: &mut Option<Recorder>, phosphor: &mut Phosphor, decay: f32, status: &Mutex<EmulatorStatus>) {
+    chip8.frame();
+    phosphor.frame(&chip8.frame_buffer, decay);
+    if let Err(e) = audio.push_frame(chip8.sound_playing, run_speed) {
+        audio_failed(audio, status, e);
+    }
+    if let Some(recorder) = recorder {
+        recorder.record_frame(&chip8.frame_buffer, chip8.sound_playing);
+    }
+}
+
+// Runs one instruction, passing on its trace.
+fn tick(chip8: &mut Chip8, key_input: u16, log: &Log) -> Result<(), ChipFault> {
+    let ticked = chip8.tick(key_input);
+    for line in chip8.take_trace() {
+        log.trace(&line);
+    }
+    ticked
+}
+
+// Stops the machine so the fault can be looked at in the debugger.
+fn machine_faulted(fault: ChipFault, current: &mut Option<ChipFault>, settings: &mut EmulatorSettings, log: &Log) {
+    log.error(&fault.to_string());
+    settings.paused = true;
+    *current = Some(fault);
+}
+
+// Turns sound off after the audio backend stops working, and lets the user know.
+fn audio_failed(audio: &mut Box<dyn AudioBackend>, status: &Mutex<EmulatorStatus>, e: Box<dyn std::error::Error>) {
+    let mut status = status.lock().unwrap();
+    status.audio_error = Some(format!("Audio output {} stopped working, sound is off: {}", audio.name(), e));
+    *audio = Box::new(NullAudio);
+    status.audio_backend = audio.name();
+}
+
+// Replaces the machine with a fresh one, keeping its settings.
+fn reset_machine(chip8: &mut Chip8, seed: Option<u64>) {
+    let (quirks_mode, platform, trace) = (chip8.quirks_mode, chip8.platform, chip8.trace);
+    *chip8 = Chip8::new(new_rng(seed));
+    chip8.quirks_mode = quirks_mode;
+    chip8.platform = platform;
+    chip8.trace = trace;
+}
+
+// Random number generator for a new machine, seeded randomly unless a seed was given.
+fn new_rng(seed: Option<u64>) -> rand_pcg::Pcg32 {
+    rand_pcg::Pcg32::new(seed.unwrap_or_else(|| thread_rng().next_u64()), 0xa02bdbf7bb3c0a7)
+}
@@ -0,0 +1,77 @@
+// Colour palettes for drawing the display.
+
+use std::str::FromStr;
+
+use egui::Color32;
+
+// Number of colours in a palette. Index 0 is the background, index 1 is
+// lit pixels.
+pub const PALETTE_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Palette {
+    #[default]
+    Classic,
+    Amber,
+    GreenPhosphor,
+    Lcd,
+    Custom([Color32; PALETTE_SIZE])
+}
+
+impl Palette {
+    // Built-in palettes, in the order they are shown in the GUI.
+    pub const PRESETS: [Palette; 4] = [Palette::Classic, Palette::Amber, Palette::GreenPhosphor, Palette::Lcd];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Palette::Classic => "Classic",
+            Palette::Amber => "Amber",
+            Palette::GreenPhosphor => "Green phosphor",
+            Palette::Lcd => "LCD",
+            Palette::Custom(_) => "Custom",
+        }
+    }
+
+    pub fn colors(&self) -> [Color32; PALETTE_SIZE] {
+        match self {
+            Palette::Classic => [
+                Color32::BLACK,
+                Color32::WHITE,
+            ],
+            Palette::Amber => [
+                Color32::from_rgb(0x1A, 0x0E, 0x00),
+                Color32::from_rgb(0xFF, 0xB0, 0x00),
+            ],
+            Palette::GreenPhosphor => [
+                Color32::from_rgb(0x00, 0x14, 0x00),
+                Color32::from_rgb(0x33, 0xFF, 0x33),
+            ],
+            Palette::Lcd => [
+                Color32::from_rgb(0x9B, 0xBC, 0x0F),
+                Color32::from_rgb(0x0F, 0x38, 0x0F),
+            ],
+            Palette::Custom(colors) => *colors,
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "classic" => Ok(Palette::Classic),
+            "amber" => Ok(Palette::Amber),
+            "green" => Ok(Palette::GreenPhosphor),
+            "lcd" => Ok(Palette::Lcd),
+            _ => Err(format!("Unknown palette '{}', expected classic, amber, green or lcd", s)),
+        }
+    }
+}
+
+// Mixes two colours, with `t` going from 0 (all `a`) to 1 (all `b`).
+pub fn blend(a: Color32, b: Color32, t: f32) -> Color32 {
+    let t = t.clamp(0.0, 1.0);
+    let mix = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * t).round() as u8;
+    Color32::from_rgb(mix(a.r(), b.r()), mix(a.g(), b.g()), mix(a.b(), b.b()))
+}
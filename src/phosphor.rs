@@ -0,0 +1,70 @@
+// Anti-flicker rendering, built from the display at the end of each emulated frame.
+// The emulator thread keeps the history, since the frontends only see some frames.
+
+use crate::chip8::{FrameBuffer, SCREEN_WIDTH, SCREEN_HEIGHT, get_pixel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    // Draw the framebuffer as is
+    Raw,
+    // Lit pixels fade out over several frames
+    Phosphor,
+    // Pixels lit in either of the last two frames are drawn
+    OrFrames
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 3] = [RenderMode::Raw, RenderMode::Phosphor, RenderMode::OrFrames];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderMode::Raw => "Raw",
+            RenderMode::Phosphor => "Phosphor decay",
+            RenderMode::OrFrames => "Blend last two frames",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Phosphor {
+    intensity: Vec<f32>,
+    prev_frame: FrameBuffer,
+    cur_frame: FrameBuffer
+}
+
+impl Phosphor {
+    // Starts the history over from the display as it is now.
+    pub fn new(frame_buffer: &FrameBuffer) -> Self {
+        let mut phosphor = Phosphor {
+            intensity: vec![0.0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            prev_frame: *frame_buffer,
+            cur_frame: *frame_buffer
+        };
+        phosphor.frame(frame_buffer, 1.0);
+        phosphor
+    }
+
+    // Feeds in the display as an emulated frame ends. `decay` is the fraction
+    // of intensity lost per frame.
+    pub fn frame(&mut self, frame_buffer: &FrameBuffer, decay: f32) {
+        self.prev_frame = self.cur_frame;
+        self.cur_frame = *frame_buffer;
+
+        let fade = 1.0 - decay.clamp(0.0, 1.0);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let val = &mut self.intensity[y * SCREEN_WIDTH + x];
+                *val = if get_pixel(frame_buffer, x, y) { 1.0 } else { *val * fade };
+            }
+        }
+    }
+
+    // Brightness of a pixel from 0 to 1 under the given mode.
+    pub fn intensity(&self, mode: RenderMode, x: usize, y: usize) -> f32 {
+        match mode {
+            RenderMode::Raw => get_pixel(&self.cur_frame, x, y) as u8 as f32,
+            RenderMode::Phosphor => self.intensity[y * SCREEN_WIDTH + x],
+            RenderMode::OrFrames => (get_pixel(&self.cur_frame, x, y) || get_pixel(&self.prev_frame, x, y)) as u8 as f32,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Recording gameplay, one emulated frame at a time, to a GIF or a PNG sequence.
+
+use std::{error::Error, fs::{self, File}, io::BufWriter, path::PathBuf};
+
+use crate::audio;
+use crate::beep::ToneSettings;
+use crate::chip8::{FrameBuffer, SCREEN_WIDTH, SCREEN_HEIGHT, get_pixel};
+use crate::palette::Palette;
+use crate::screenshot;
+
+const FRAMES_PER_SECOND: u64 = 60;
+// Browsers slow down GIF frames shorter than this (in 1/100 s)
+const MIN_GIF_DELAY: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    Gif,
+    PngSequence,
+    Wav         // only the sound
+}
+
+impl RecordingFormat {
+    pub const ALL: [RecordingFormat; 3] = [RecordingFormat::Gif, RecordingFormat::PngSequence, RecordingFormat::Wav];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "Animated GIF",
+            RecordingFormat::PngSequence => "PNG sequence",
+            RecordingFormat::Wav => "Sound only (WAV)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingSettings {
+    pub path: PathBuf,      // GIF or WAV file, or directory for a PNG sequence
+    pub format: RecordingFormat,
+    pub scale: usize,
+    pub palette: Palette,
+    pub audio: bool,        // also write the beeper to a WAV file
+    pub tone: ToneSettings
+}
+
+pub struct Recorder {
+    settings: RecordingSettings,
+    frames: Vec<FrameBuffer>,
+    sound: Vec<bool>
+}
+
+impl Recorder {
+    pub fn new(settings: RecordingSettings) -> Self {
+        Recorder {
+            settings,
+            frames: vec![],
+            sound: vec![]
+        }
+    }
+
+    // Records the state of the machine at the end of an emulated frame.
+    pub fn record_frame(&mut self, frame_buffer: &FrameBuffer, sound_playing: bool) {
+        self.frames.push(*frame_buffer);
+        self.sound.push(sound_playing);
+    }
+
+    // Encodes everything recorded so far. This can take a while, so it
+    // shouldn't be called from the emulation thread.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self.settings.format {
+            RecordingFormat::Gif => self.write_gif()?,
+            RecordingFormat::PngSequence => self.write_png_sequence()?,
+            RecordingFormat::Wav => (),
+        }
+        if self.settings.audio || self.settings.format == RecordingFormat::Wav {
+            self.write_audio()?;
+        }
+        Ok(())
+    }
+
+    fn write_gif(&self) -> Result<(), Box<dyn Error>> {
+        let scale = self.settings.scale.max(1);
+        let width = SCREEN_WIDTH * scale;
+        let height = SCREEN_HEIGHT * scale;
+        let colors = self.settings.palette.colors();
+        let global_palette: Vec<u8> = colors.iter().flat_map(|c| [c.r(), c.g(), c.b()]).collect();
+
+        let file = BufWriter::new(File::create(&self.settings.path)?);
+        let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &global_palette)?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+
+        let mut write_frame = |frame_buffer: &FrameBuffer, delay: u64| -> Result<(), gif::EncodingError> {
+            let mut pixels = vec![0u8; width * height];
+            for y in 0..height {
+                for x in 0..width {
+                    pixels[y * width + x] = get_pixel(frame_buffer, x / scale, y / scale) as u8;
+                }
+            }
+            let mut frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, &pixels, None);
+            frame.delay = delay.min(u16::MAX as u64) as u16;
+            encoder.write_frame(&frame)
+        };
+
+        // Only frames that differ from the last one are written. Delays are
+        // worked out from absolute frame numbers so rounding errors don't add up.
+        let centis = |frame: usize| (frame as u64 * 100 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND;
+        let (first, rest) = match self.frames.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let mut shown = first;
+        let mut shown_since = 0;
+        for (i, frame_buffer) in rest.iter().enumerate().map(|(i, f)| (i + 1, f)) {
+            if frame_buffer == shown {
+                continue;
+            }
+            let delay = centis(i) - centis(shown_since);
+            if delay >= MIN_GIF_DELAY {
+                write_frame(shown, delay)?;
+                shown_since = i;
+            }
+            shown = frame_buffer;
+        }
+        let delay = centis(self.frames.len()) - centis(shown_since);
+        write_frame(shown, delay.max(MIN_GIF_DELAY))?;
+        Ok(())
+    }
+
+    fn write_png_sequence(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.settings.path)?;
+        for (i, frame_buffer) in self.frames.iter().enumerate() {
+            let path = self.settings.path.join(format!("frame_{:06}.png", i));
+            screenshot::save_screenshot(frame_buffer, self.settings.palette, self.settings.scale, &path)?;
+        }
+        Ok(())
+    }
+
+    fn write_audio(&self) -> Result<(), Box<dyn Error>> {
+        let path = match self.settings.format {
+            RecordingFormat::Gif => self.settings.path.with_extension("wav"),
+            RecordingFormat::PngSequence => self.settings.path.join("audio.wav"),
+            RecordingFormat::Wav => self.settings.path.clone(),
+        };
+        audio::export_wav(&self.sound, self.settings.tone, &path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+// Rendering the display to images and saving them as PNG files.
+
+use std::{error::Error, fs::File, io::BufWriter, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use egui::{Color32, ColorImage};
+use crate::chip8::{FrameBuffer, SCREEN_WIDTH, SCREEN_HEIGHT, get_pixel};
+use crate::palette::Palette;
+
+// Renders a framebuffer to an image, with every pixel drawn as a `scale` by `scale` square.
+pub fn render(frame_buffer: &FrameBuffer, palette: Palette, scale: usize) -> ColorImage {
+    let scale = scale.max(1);
+    let colors = palette.colors();
+    let width = SCREEN_WIDTH * scale;
+    let height = SCREEN_HEIGHT * scale;
+    let mut image = ColorImage::new([width, height], colors[0]);
+    for y in 0..height {
+        for x in 0..width {
+            if get_pixel(frame_buffer, x / scale, y / scale) {
+                image.pixels[y * width + x] = colors[1];
+            }
+        }
+    }
+    image
+}
+
+// Writes an image to a PNG file.
+pub fn save_png(image: &ColorImage, path: &Path) -> Result<(), Box<dyn Error>> {
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.size[0] as u32, image.size[1] as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let data: Vec<u8> = image.pixels.iter()
+        .flat_map(|c: &Color32| [c.r(), c.g(), c.b()])
+        .collect();
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+// Renders a framebuffer and saves it to a PNG file in one go.
+pub fn save_screenshot(frame_buffer: &FrameBuffer, palette: Palette, scale: usize, path: &Path) -> Result<(), Box<dyn Error>> {
+    save_png(&render(frame_buffer, palette, scale), path)
+}
+
+// File name for a capture taken now, so quick saves don't overwrite each other.
+pub fn default_file_name(extension: &str) -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    if extension.is_empty() {
+        format!("chip8_{}", millis)
+    } else {
+        format!("chip8_{}.{}", millis, extension)
+    }
+}
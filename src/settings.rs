@@ -0,0 +1,300 @@
+// Saving settings between runs, in a TOML file in the user's config directory.
+
+use std::{collections::HashMap, env, error::Error, fs, path::PathBuf};
+
+use egui::{Color32, Pos2, Vec2};
+use toml::value::{Table, Value};
+use crate::beep::Waveform;
+use crate::chip8::{Platform, MAX_STACK_SIZE};
+use crate::debugger::{DebuggerState, RUN_SPEED_RANGE};
+use crate::gui::ScaleMode;
+use crate::palette::{Palette, PALETTE_SIZE};
+use crate::phosphor::RenderMode;
+use crate::recorder::RecordingFormat;
+
+const APP_DIR: &str = "chip8";
+const FILE_NAME: &str = "settings.toml";
+// Most ROMs kept in the recently opened list
+pub const MAX_RECENT_ROMS: usize = 10;
+
+// Where the GUI windows were, so they open in the same places next time.
+#[derive(Clone, Default)]
+pub struct Layout {
+    pub window_pos: Option<Pos2>,       // main window, on the desktop
+    pub window_size: Option<Vec2>,
+    pub windows: HashMap<String, Pos2>  // debugger windows, by title
+}
+
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub debugger: DebuggerState,
+    pub layout: Layout
+}
+
+impl Settings {
+    // Reads the settings file, falling back to defaults if it's missing or broken.
+    pub fn load() -> Self {
+        let path = match settings_path() {
+            Some(path) if path.exists() => path,
+            _ => return Self::default(),
+        };
+        let result = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| text.parse::<Value>().map_err(|e| e.to_string()));
+        match result {
+            Ok(Value::Table(table)) => Self::from_table(&table),
+            Ok(_) => Self::default(),
+            Err(e) => {
+                eprintln!("Couldn't read settings from {}, using defaults: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = settings_path().ok_or("Couldn't find a config directory")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, toml::to_string(&Value::Table(self.to_table()))?)?;
+        Ok(())
+    }
+
+    fn to_table(&self) -> Table {
+        let dbg = &self.debugger;
+        let mut emulator = Table::new();
+        emulator.insert("run_speed".into(), Value::Float(dbg.run_speed as f64));
+        emulator.insert("ticks_per_frame".into(), Value::Integer(dbg.ticks_per_frame as i64));
+        emulator.insert("platform".into(), Value::String(dbg.platform.name().into()));
+        emulator.insert("shift_quirk".into(), Value::Boolean(dbg.quirks.shift));
+        emulator.insert("load_store_quirk".into(), Value::Boolean(dbg.quirks.ldi));
+        emulator.insert("key_press_quirk".into(), Value::Boolean(dbg.quirks.key_press));
+        emulator.insert("stack_depth".into(), Value::Integer(dbg.quirks.stack_depth as i64));
+        emulator.insert("memory_wrap".into(), Value::Boolean(dbg.quirks.memory_wrap));
+
+        let mut display = Table::new();
+        display.insert("palette".into(), palette_to_value(dbg.palette));
+        display.insert("rom_palettes".into(), Value::Table(dbg.rom_palettes.iter()
+            .map(|(path, palette)| (path.clone(), palette_to_value(*palette)))
+            .collect()));
+        display.insert("render_mode".into(), Value::String(dbg.render_mode.name().into()));
+        display.insert("phosphor_decay".into(), Value::Float(dbg.phosphor_decay as f64));
+        display.insert("scale_mode".into(), Value::String(dbg.scale_mode.name().into()));
+
+        let mut capture = Table::new();
+        capture.insert("screenshot_scale".into(), Value::Integer(dbg.screenshot_scale as i64));
+        capture.insert("recording_format".into(), Value::String(dbg.recording_format.name().into()));
+        capture.insert("recording_audio".into(), Value::Boolean(dbg.recording_audio));
+
+        let mut sound = Table::new();
+        sound.insert("pitch".into(), Value::Float(dbg.tone.pitch as f64));
+        sound.insert("waveform".into(), Value::String(dbg.tone.waveform.name().into()));
+        sound.insert("volume".into(), Value::Float(dbg.tone.volume as f64));
+        sound.insert("muted".into(), Value::Boolean(dbg.muted));
+
+        let mut files = Table::new();
+        if let Some(dir) = &dbg.last_rom_dir {
+            files.insert("last_rom_dir".into(), Value::String(dir.display().to_string()));
+        }
+        files.insert("recent_roms".into(), Value::Array(dbg.recent_roms.iter().cloned().map(Value::String).collect()));
+        files.insert("watch_rom".into(), Value::Boolean(dbg.watch_rom));
+        files.insert("library_dirs".into(), Value::Array(dbg.library_dirs.iter()
+            .map(|dir| Value::String(dir.display().to_string()))
+            .collect()));
+
+        let mut layout = Table::new();
+        if let Some(pos) = self.layout.window_pos {
+            layout.insert("window_pos".into(), pair_to_value(pos.x, pos.y));
+        }
+        if let Some(size) = self.layout.window_size {
+            layout.insert("window_size".into(), pair_to_value(size.x, size.y));
+        }
+        layout.insert("windows".into(), Value::Table(self.layout.windows.iter()
+            .map(|(name, pos)| (name.clone(), pair_to_value(pos.x, pos.y)))
+            .collect()));
+
+        let mut table = Table::new();
+        table.insert("emulator".into(), Value::Table(emulator));
+        table.insert("display".into(), Value::Table(display));
+        table.insert("capture".into(), Value::Table(capture));
+        table.insert("sound".into(), Value::Table(sound));
+        table.insert("files".into(), Value::Table(files));
+        table.insert("layout".into(), Value::Table(layout));
+        table
+    }
+
+    // Anything missing or invalid is left at its default.
+    fn from_table(table: &Table) -> Self {
+        let mut settings = Self::default();
+        let dbg = &mut settings.debugger;
+        let section = |name: &str| table.get(name).and_then(Value::as_table).cloned().unwrap_or_default();
+
+        let emulator = section("emulator");
+        if let Some(speed) = get_float(&emulator, "run_speed") {
+            dbg.run_speed = speed.clamp(*RUN_SPEED_RANGE.start(), *RUN_SPEED_RANGE.end());
+        }
+        if let Some(ticks) = emulator.get("ticks_per_frame").and_then(Value::as_integer) {
+            dbg.ticks_per_frame = ticks.clamp(1, u32::MAX as i64) as u32;
+        }
+        if let Some(platform) = get_named(&emulator, "platform", &Platform::ALL, Platform::name) {
+            dbg.platform = platform;
+            dbg.quirks = platform.quirks();
+        }
+        if let Some(shift) = emulator.get("shift_quirk").and_then(Value::as_bool) {
+            dbg.quirks.shift = shift;
+        }
+        if let Some(ldi) = emulator.get("load_store_quirk").and_then(Value::as_bool) {
+            dbg.quirks.ldi = ldi;
+        }
+        if let Some(key_press) = emulator.get("key_press_quirk").and_then(Value::as_bool) {
+            dbg.quirks.key_press = key_press;
+        }
+        if let Some(depth) = emulator.get("stack_depth").and_then(Value::as_integer) {
+            dbg.quirks.stack_depth = depth.clamp(1, MAX_STACK_SIZE as i64) as usize;
+        }
+        if let Some(wrap) = emulator.get("memory_wrap").and_then(Value::as_bool) {
+            dbg.quirks.memory_wrap = wrap;
+        }
+
+        let display = section("display");
+        if let Some(palette) = display.get("palette").and_then(palette_from_value) {
+            dbg.palette = palette;
+        }
+        if let Some(Value::Table(rom_palettes)) = display.get("rom_palettes") {
+            dbg.rom_palettes = rom_palettes.iter()
+                .filter_map(|(path, palette)| Some((path.clone(), palette_from_value(palette)?)))
+                .collect();
+        }
+        if let Some(mode) = get_named(&display, "render_mode", &RenderMode::ALL, RenderMode::name) {
+            dbg.render_mode = mode;
+        }
+        if let Some(decay) = get_float(&display, "phosphor_decay") {
+            dbg.phosphor_decay = decay.clamp(0.01, 1.0);
+        }
+        if let Some(mode) = get_named(&display, "scale_mode", &ScaleMode::ALL, ScaleMode::name) {
+            dbg.scale_mode = mode;
+        }
+
+        let capture = section("capture");
+        if let Some(scale) = capture.get("screenshot_scale").and_then(Value::as_integer) {
+            dbg.screenshot_scale = scale.clamp(1, 32) as usize;
+        }
+        if let Some(format) = get_named(&capture, "recording_format", &RecordingFormat::ALL, RecordingFormat::name) {
+            dbg.recording_format = format;
+        }
+        if let Some(audio) = capture.get("recording_audio").and_then(Value::as_bool) {
+            dbg.recording_audio = audio;
+        }
+
+        let sound = section("sound");
+        if let Some(pitch) = get_float(&sound, "pitch") {
+            dbg.tone.pitch = pitch.clamp(50.0, 2000.0);
+        }
+        if let Some(waveform) = get_named(&sound, "waveform", &Waveform::ALL, Waveform::name) {
+            dbg.tone.waveform = waveform;
+        }
+        if let Some(volume) = get_float(&sound, "volume") {
+            dbg.tone.volume = volume.clamp(0.0, 1.0);
+        }
+        if let Some(muted) = sound.get("muted").and_then(Value::as_bool) {
+            dbg.muted = muted;
+        }
+
+        let files = section("files");
+        dbg.last_rom_dir = files.get("last_rom_dir").and_then(Value::as_str).map(PathBuf::from);
+        if let Some(Value::Array(recent)) = files.get("recent_roms") {
+            dbg.recent_roms = recent.iter().filter_map(Value::as_str).map(String::from).take(MAX_RECENT_ROMS).collect();
+        }
+        if let Some(watch) = files.get("watch_rom").and_then(Value::as_bool) {
+            dbg.watch_rom = watch;
+        }
+        if let Some(Value::Array(dirs)) = files.get("library_dirs") {
+            dbg.library_dirs = dirs.iter().filter_map(Value::as_str).map(PathBuf::from).collect();
+        }
+
+        let layout = section("layout");
+        settings.layout.window_pos = layout.get("window_pos").and_then(pair_from_value).map(|(x, y)| Pos2 { x, y });
+        settings.layout.window_size = layout.get("window_size").and_then(pair_from_value).map(|(x, y)| Vec2 { x, y });
+        if let Some(Value::Table(windows)) = layout.get("windows") {
+            settings.layout.windows = windows.iter()
+                .filter_map(|(name, pos)| pair_from_value(pos).map(|(x, y)| (name.clone(), Pos2 { x, y })))
+                .collect();
+        }
+        settings
+    }
+}
+
+// The settings file, e.g. ~/.config/chip8/settings.toml on Linux.
+pub fn settings_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(APP_DIR).join(FILE_NAME))
+}
+
+#[cfg(target_os = "windows")]
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(PathBuf::from)
+}
+
+#[cfg(target_os = "macos")]
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Application Support"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+// Infinities and NaN count as invalid, they can't be clamped.
+fn get_float(table: &Table, key: &str) -> Option<f32> {
+    let val = match table.get(key)? {
+        Value::Float(val) => *val as f32,
+        Value::Integer(val) => *val as f32,
+        _ => return None
+    };
+    val.is_finite().then_some(val)
+}
+
+// Looks up an enum value saved by its name.
+fn get_named<T: Copy>(table: &Table, key: &str, all: &[T], name: fn(&T) -> &'static str) -> Option<T> {
+    let saved = table.get(key)?.as_str()?;
+    all.iter().find(|val| name(val) == saved).copied()
+}
+
+fn pair_to_value(a: f32, b: f32) -> Value {
+    Value::Array(vec![Value::Float(a as f64), Value::Float(b as f64)])
+}
+
+fn pair_from_value(value: &Value) -> Option<(f32, f32)> {
+    match value.as_array()?.as_slice() {
+        [a, b] => Some((a.as_float()? as f32, b.as_float()? as f32)),
+        _ => None
+    }
+}
+
+// Presets are saved by name, custom palettes as a list of "#rrggbb" colours.
+fn palette_to_value(palette: Palette) -> Value {
+    match palette {
+        Palette::Custom(colors) => Value::Array(colors.iter()
+            .map(|c| Value::String(format!("#{:02x}{:02x}{:02x}", c.r(), c.g(), c.b())))
+            .collect()),
+        preset => Value::String(preset.name().into()),
+    }
+}
+
+fn palette_from_value(value: &Value) -> Option<Palette> {
+    match value {
+        Value::String(name) => Palette::PRESETS.iter().find(|p| p.name() == name).copied(),
+        // older versions saved four colours, of which only the first two were used
+        Value::Array(colors) if colors.len() == PALETTE_SIZE || colors.len() == 4 => {
+            let mut parsed = [Color32::BLACK; PALETTE_SIZE];
+            for (color, value) in parsed.iter_mut().zip(colors) {
+                let hex = value.as_str()?.strip_prefix('#')?;
+                let rgb = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)?;
+                *color = Color32::from_rgb((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+            }
+            Some(Palette::Custom(parsed))
+        },
+        _ => None
+    }
+}
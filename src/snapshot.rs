@@ -0,0 +1,108 @@
+// Copies of the machine handed from the emulator thread to the frontends,
+// so drawing never holds up emulation.
+//
+// The emulator owns the machine and publishes a snapshot every so often.
+// Only the newest one matters: if the frontend hasn't taken the last one
+// yet, the new one waits and replaces anything older that's waiting, so
+// neither side ever blocks on the other.
+
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+use crate::chip8::{Chip8, ChipFault, FrameBuffer, KeyWait, Platform, INSTRUCTION_SIZE, MEMORY_SIZE, REGISTER_COUNT};
+use crate::phosphor::Phosphor;
+use crate::translator;
+
+pub struct Snapshot {
+    pub frame_buffer: FrameBuffer,
+    pub phosphor: Phosphor,         // display history up to the last emulated frame
+    pub display_version: u64,       // goes up whenever the display is drawn to
+    pub frame_count: u64,
+    pub registers: [u8; REGISTER_COUNT],
+    pub ir: u16,
+    pub pc: u16,
+    pub dt: u8,
+    pub stack: Vec<u16>,
+    pub stack_depth: usize,
+    pub key_wait: KeyWait,
+    pub keys_tested: u16,
+    pub platform: Platform,
+    pub memory: [u8; MEMORY_SIZE],
+    pub state: Vec<u8>,             // save state of the machine
+    pub fault: Option<ChipFault>    // the machine stops until this is dealt with
+}
+
+impl Snapshot {
+    pub fn new(chip8: &Chip8, phosphor: Phosphor, display_version: u64, fault: Option<ChipFault>) -> Self {
+        Snapshot {
+            frame_buffer: chip8.frame_buffer,
+            phosphor,
+            display_version,
+            frame_count: chip8.frame_count,
+            registers: chip8.registers,
+            ir: chip8.ir,
+            pc: chip8.pc,
+            dt: chip8.dt,
+            stack: chip8.stack().to_vec(),
+            stack_depth: chip8.quirks_mode.stack_depth,
+            key_wait: chip8.key_wait,
+            keys_tested: chip8.keys_tested(),
+            platform: chip8.platform,
+            memory: chip8.memory,
+            state: chip8.save_state(),
+            fault
+        }
+    }
+
+    // Disassembles the instruction `offset` instructions away from the
+    // program counter, or None if that's outside memory.
+    pub fn disassemble(&self, offset: i32) -> Option<(u16, String)> {
+        let addr = self.pc as i32 + offset * INSTRUCTION_SIZE as i32;
+        if addr < 0 || addr + INSTRUCTION_SIZE as i32 > MEMORY_SIZE as i32 {
+            return None;
+        }
+        let opcode = u16::from_be_bytes([self.memory[addr as usize], self.memory[addr as usize + 1]]);
+        Some((addr as u16, translator::translate(opcode, self.platform)))
+    }
+}
+
+pub struct SnapshotSender {
+    sender: SyncSender<Snapshot>,
+    waiting: Option<Snapshot>   // newer than the one the frontend hasn't taken yet
+}
+
+impl SnapshotSender {
+    pub fn publish(&mut self, snapshot: Snapshot) {
+        self.waiting = Some(snapshot);
+        self.flush();
+    }
+
+    // Passes on the waiting snapshot if there's room for it now.
+    pub fn flush(&mut self) {
+        if let Some(snapshot) = self.waiting.take() {
+            if let Err(TrySendError::Full(snapshot)) = self.sender.try_send(snapshot) {
+                self.waiting = Some(snapshot);
+            }
+        }
+    }
+}
+
+pub struct SnapshotReceiver {
+    receiver: Receiver<Snapshot>,
+    latest: Arc<Snapshot>
+}
+
+impl SnapshotReceiver {
+    // The newest snapshot published so far.
+    pub fn latest(&mut self) -> Arc<Snapshot> {
+        while let Ok(snapshot) = self.receiver.try_recv() {
+            self.latest = Arc::new(snapshot);
+        }
+        self.latest.clone()
+    }
+}
+
+pub fn channel(first: Snapshot) -> (SnapshotSender, SnapshotReceiver) {
+    let (sender, receiver) = mpsc::sync_channel(1);
+    (SnapshotSender { sender, waiting: None }, SnapshotReceiver { receiver, latest: Arc::new(first) })
+}
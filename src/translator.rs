@@ -1,61 +1,69 @@
+use crate::chip8::Platform;
+use crate::instruction;
 
-use crate::chip8::get_nnn;
+// Disassembles an opcode, showing ones that aren't instructions as XXXX.
+pub fn translate(opcode: u16, platform: Platform) -> String {
+    match instruction::decode(opcode, platform) {
+        Some(instruction) => instruction.to_string(),
+        None => format!("XXXX {:04x}", opcode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate_op(opcode: u16) -> String {
+        translate(opcode, Platform::default())
+    }
 
-pub fn translate(b1: u8, b2: u8) -> String {
-    let bits = (b1 >> 4, b1 & 0x0F, b2 >> 4, b2 & 0x0F);
-    match bits.0 {
-        0x0 => match get_nnn(b1, b2) {
-            0x0E0 => String::from("CLS"),
-            0x0EE => String::from("RET"),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2)
+    #[test]
+    fn translates_every_opcode() {
+        let cases = [
+            (0x00E0, "CLS"),
+            (0x00EE, "RET"),
+            (0x1ABC, "JP   0xabc"),
+            (0x2ABC, "CALL 0xabc"),
+            (0x3A12, "SE   Va,  0x12"),
+            (0x4A12, "SNE  Va,  0x12"),
+            (0x5AB0, "SE   Va,  Vb"),
+            (0x6A12, "LD   Va,  0x12"),
+            (0x7A12, "ADD  Va,  0x12"),
+            (0x8AB0, "LD   Va,  Vb"),
+            (0x8AB1, "OR   Va,  Vb"),
+            (0x8AB2, "AND  Va,  Vb"),
+            (0x8AB3, "XOR  Va,  Vb"),
+            (0x8AB4, "ADD  Va,  Vb"),
+            (0x8AB5, "SUB  Va,  Vb"),
+            (0x8AB6, "SHR  Va,  <Vb>"),
+            (0x8AB7, "SUBN Va,  Vb"),
+            (0x8ABE, "SHL  Va,  <Vb>"),
+            (0x9AB0, "SNE  Va,  Vb"),
+            (0xAABC, "LD   I,   0xabc"),
+            (0xBABC, "JP   V0,  0xabc"),
+            (0xCA12, "RND  Va,  0x12"),
+            (0xDAB5, "DRW  Va,  Vb,  0x5"),
+            (0xEA9E, "SKP  Va"),
+            (0xEAA1, "SKNP Va"),
+            (0xFA07, "LD   Va,  DT"),
+            (0xFA0A, "LD   Va,  K"),
+            (0xFA15, "LD   DT,  Va"),
+            (0xFA18, "LD   ST,  Va"),
+            (0xFA1E, "ADD  I,   Va"),
+            (0xFA29, "LD   F,   Va"),
+            (0xFA33, "LD   B,   Va"),
+            (0xFA55, "LD   [I], Va"),
+            (0xFA65, "LD   Va,  [I]"),
+        ];
+        for (opcode, text) in cases {
+            assert_eq!(translate_op(opcode), text, "{:04x}", opcode);
         }
-        0x1 => format!("JP   0x{:03x}", get_nnn(b1, b2)),
-        0x2 => format!("CALL 0x{:03x}", get_nnn(b1, b2)),
-        0x3 => format!("SE   V{:x},  0x{:02x}", bits.1, b2),
-        0x4 => format!("SNE  V{:x},  0x{:02x}", bits.1, b2),
-        0x5 => match bits.3 {
-            0x0 => format!("SE   V{:x},  V{:x}", bits.1, bits.2),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2),
-        },
-        0x6 => format!("LD   V{:x},  0x{:02x}", bits.1, b2),
-        0x7 => format!("ADD  V{:x},  0x{:02x}", bits.1, b2),
-        0x8 => match bits.3 {
-            0x0 => format!("LD   V{:x},  V{:x}", bits.1, bits.2),
-            0x1 => format!("OR   V{:x},  V{:x}", bits.1, bits.2),
-            0x2 => format!("AND  V{:x},  V{:x}", bits.1, bits.2),
-            0x3 => format!("XOR  V{:x},  V{:x}", bits.1, bits.2),
-            0x4 => format!("ADD  V{:x},  V{:x}", bits.1, bits.2),
-            0x5 => format!("SUB  V{:x},  V{:x}", bits.1, bits.2),
-            0x6 => format!("SHR  V{:x},  <V{:x}>", bits.1, bits.2),
-            0x7 => format!("SUBN V{:x},  V{:x}", bits.1, bits.2),
-            0xE => format!("SHL  V{:x},  <V{:x}>", bits.1, bits.2),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2)
-        },
-        0x9 => match bits.3 {
-            0x0 => format!("SNE  V{:x},  V{:x}", bits.1, bits.2),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2)
-        },
-        0xA => format!("LD   I,   0x{:03x}", get_nnn(b1, b2)),
-        0xB => format!("JP   V0,  0x{:03x}", get_nnn(b1, b2)),
-        0xC => format!("RND  V{:x},  0x{:02x}", bits.1, b2),
-        0xD => format!("DRW  V{:x},  V{:x},  0x{:x}", bits.1, bits.2, bits.3),
-        0xE => match b2 {
-            0x9E => format!("SKP  V{:x}", bits.1),
-            0xA1 => format!("SKNP V{:x}", bits.1),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2),
-        },
-        0xF => match b2 {
-            0x07 => format!("LD   V{:x},  DT", bits.1),
-            0x0A => format!("LD   V{:x},  K", bits.1),
-            0x15 => format!("LD   DT,  V{:x}", bits.1),
-            0x18 => format!("LD   ST,  V{:x}", bits.1),
-            0x1E => format!("ADD  I,   V{:x}", bits.1),
-            0x29 => format!("LD   F,   V{:x}", bits.1),
-            0x33 => format!("LD   B,   V{:x}", bits.1),
-            0x55 => format!("LD   [I], V{:x}", bits.1),
-            0x65 => format!("LD   V{:x},  [I]", bits.1),
-            _ => format!("XXXX {:02x}{:02x}", b1, b2)
+    }
+
+    #[test]
+    fn translates_unknown_opcodes() {
+        for opcode in [0x0000, 0x0123, 0x5AB1, 0x8AB8, 0x8ABF, 0x9AB1, 0xEA00, 0xFA00, 0xFAFF] {
+            assert_eq!(translate_op(opcode), format!("XXXX {:04x}", opcode));
         }
-        _ => format!("XXXX {:02x}{:02x}", b1, b2)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,259 @@
+// Terminal frontend, for when there's no window system available.
+
+use std::{error::Error, fs, io::{self, Write}, path::PathBuf, time::{Duration, Instant}};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags};
+use crate::chip8::{FrameBuffer, KeyWait, SCREEN_WIDTH, SCREEN_HEIGHT, REGISTER_COUNT, get_pixel};
+use crate::debugger::{DebuggerState, DebugInstructions, EmulatorSettings, EmulatorStatus, RUN_SPEED_RANGE};
+use crate::input::InputDriver;
+use crate::screenshot;
+use crate::snapshot::SnapshotReceiver;
+use crate::watcher::RomWatcher;
+
+const REDRAW_TIME: Duration = Duration::from_millis(16);
+// Terminals don't always report key releases, so keys count as held for
+// this long after the last press or repeat.
+const KEY_HOLD_TIME: Duration = Duration::from_millis(200);
+const INSTRUCTION_VIEW_RANGE: i32 = 3;
+const SIDE_PANE_COLUMN: u16 = SCREEN_WIDTH as u16 + 3;
+const STOPPED_TEXT: &str = "The emulator has stopped, restart to carry on";
+const HELP_TEXT: &str = "esc: quit  p: pause  n: step  b: frame  [ ]: speed  F5: reset";
+const FAULT_HELP_TEXT: &str = "o: continue as NOP  m: dump state  F5: reset";
+
+pub struct ChipTUI {
+    input_mutex: Arc<Mutex<u16>>,
+    snapshots: SnapshotReceiver,
+    debugger: DebuggerState,
+    sent_settings: EmulatorSettings,    // what the emulator was last told
+    debug_sender: Sender<DebugInstructions>,
+    status: Arc<Mutex<EmulatorStatus>>,
+    key_times: [Option<Instant>; 16],   // when each key was last pressed, if held
+    key_releases: bool,                 // whether the terminal reports key releases
+    watcher: RomWatcher,
+    message: String                     // result of the last thing the user did
+}
+
+impl ChipTUI {
+    pub fn new(input_mutex: Arc<Mutex<u16>>, snapshots: SnapshotReceiver, debugger: DebuggerState, debug_sender: Sender<DebugInstructions>, status: Arc<Mutex<EmulatorStatus>>) -> Self {
+        ChipTUI {
+            input_mutex,
+            snapshots,
+            sent_settings: EmulatorSettings::new(&debugger),
+            debugger,
+            debug_sender,
+            status,
+            key_times: [None; 16],
+            key_releases: false,
+            watcher: RomWatcher::new(),
+            message: String::new()
+        }
+    }
+
+    // Takes over the terminal until the user quits, returning the settings as they were left.
+    pub fn run(mut self) -> Result<DebuggerState, Box<dyn Error>> {
+        let mut stdout = io::stdout();
+        terminal::enable_raw_mode()?;
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        self.key_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if self.key_releases {
+            execute!(stdout, event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+
+        let result = self.main_loop(&mut stdout);
+
+        if self.key_releases {
+            execute!(stdout, event::PopKeyboardEnhancementFlags)?;
+        }
+        execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+        result.map(|_| self.debugger)
+    }
+
+    fn main_loop(&mut self, stdout: &mut io::Stdout) -> Result<(), Box<dyn Error>> {
+        execute!(stdout, terminal::Clear(terminal::ClearType::All))?;
+        loop {
+            let redraw_at = Instant::now() + REDRAW_TIME;
+            while event::poll(redraw_at.saturating_duration_since(Instant::now()))? {
+                match event::read()? {
+                    Event::Key(key) if !self.handle_key(key) => return Ok(()),
+                    Event::Resize(_, _) => execute!(stdout, terminal::Clear(terminal::ClearType::All))?,
+                    _ => ()
+                }
+            }
+
+            let mut keys = 0u16;
+            for (key, time) in self.key_times.iter_mut().enumerate() {
+                if let Some(pressed) = time {
+                    if !self.key_releases && pressed.elapsed() > KEY_HOLD_TIME {
+                        *time = None;
+                    } else {
+                        keys |= 1 << key;
+                    }
+                }
+            }
+            *self.input_mutex.lock().unwrap() = keys;
+
+            match (self.debugger.watch_rom, self.debugger.rom_path.clone()) {
+                (true, Some(path)) if self.watcher.poll(&path) => self.send(DebugInstructions::Reload(path)),
+                (false, _) => self.watcher.stop(),
+                _ => ()
+            }
+            // the emulator stops on a fault, so stay paused after it's been dealt with
+            if self.snapshots.latest().fault.is_some() {
+                self.debugger.paused = true;
+            }
+            if self.sent_settings.sync(&self.debugger, &self.debug_sender).is_err() {
+                self.message = STOPPED_TEXT.to_string();
+            }
+
+            self.draw(stdout)?;
+        }
+    }
+
+    // Writes a report of the current fault and a save state next to it, in the working directory.
+    fn dump_fault(&mut self) {
+        let fault = match self.snapshots.latest().fault.clone() {
+            Some(fault) => fault,
+            None => return,
+        };
+        let path = PathBuf::from(screenshot::default_file_name("txt"));
+        let state_path = path.with_extension("c8s");
+        let result = fs::write(&path, fault.report()).and_then(|_| fs::write(&state_path, &fault.state));
+        self.message = match result {
+            Ok(()) => format!("Saved {} and {}", path.display(), state_path.display()),
+            Err(e) => format!("Couldn't save state: {}", e),
+        };
+    }
+
+    // Handles a key event, returning false if the user wants to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // before the keypad, since C is also a key there
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return false;
+        }
+        if let KeyCode::Char(c) = key.code {
+            if let Some(chip_key) = InputDriver::convert_char(c) {
+                self.key_times[chip_key as usize] = match key.kind {
+                    KeyEventKind::Release => None,
+                    _ => Some(Instant::now())
+                };
+                return true;
+            }
+        }
+        if key.kind == KeyEventKind::Release {
+            return true;
+        }
+
+        if key.code == KeyCode::Char('m') {
+            self.dump_fault();
+            return true;
+        }
+
+        let paused = self.debugger.paused;
+        let run_speed = self.debugger.run_speed;
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('p') => self.debugger.paused = !paused,
+            KeyCode::Char('n') if paused => self.send(DebugInstructions::Step),
+            KeyCode::Char('b') if paused => self.send(DebugInstructions::Frame),
+            KeyCode::F(5) if paused => self.send(DebugInstructions::Reset),
+            KeyCode::Char('o') => self.send(DebugInstructions::ContinueAsNop),
+            KeyCode::Char('[') => self.debugger.run_speed = (run_speed / 2.0).max(*RUN_SPEED_RANGE.start()),
+            KeyCode::Char(']') => self.debugger.run_speed = (run_speed * 2.0).min(*RUN_SPEED_RANGE.end()),
+            _ => ()
+        }
+        true
+    }
+
+    // Sends a command to the emulator thread, noting if it's no longer there.
+    fn send(&mut self, instruction: DebugInstructions) {
+        if self.debug_sender.send(instruction).is_err() {
+            self.message = STOPPED_TEXT.to_string();
+        }
+    }
+
+    fn draw(&mut self, stdout: &mut io::Stdout) -> Result<(), Box<dyn Error>> {
+        let (paused, run_speed) = (self.debugger.paused, self.debugger.run_speed);
+
+        let snapshot = self.snapshots.latest();
+        let mut disassembly = vec![];
+        for i in -INSTRUCTION_VIEW_RANGE..=INSTRUCTION_VIEW_RANGE {
+            disassembly.push(match snapshot.disassemble(i) {
+                Some((addr, translated)) => {
+                    let marker = if i == 0 { '>' } else { ' ' };
+                    format!("{} {:03x} {}", marker, addr, translated)
+                }
+                None => String::new()
+            });
+        }
+        let (frame_buffer, registers, ir, pc, dt, key_wait) = (snapshot.frame_buffer, snapshot.registers, snapshot.ir, snapshot.pc, snapshot.dt, snapshot.key_wait);
+
+        // display, two pixels per character cell
+        let border = "─".repeat(SCREEN_WIDTH);
+        queue!(stdout, cursor::MoveTo(0, 0), style::Print(format!("┌{}┐", border)))?;
+        for (row, line) in display_lines(&frame_buffer).iter().enumerate() {
+            queue!(stdout, cursor::MoveTo(0, row as u16 + 1), style::Print(format!("│{}│", line)))?;
+        }
+        queue!(stdout, cursor::MoveTo(0, SCREEN_HEIGHT as u16 / 2 + 1), style::Print(format!("└{}┘", border)))?;
+
+        // registers
+        for i in 0..REGISTER_COUNT / 2 {
+            let high = i + REGISTER_COUNT / 2;
+            queue!(stdout, cursor::MoveTo(SIDE_PANE_COLUMN, i as u16 + 1),
+                style::Print(format!("V{:x}: 0x{:02x}  V{:x}: 0x{:02x}", i, registers[i], high, registers[high])))?;
+        }
+        let status = if paused { "PAUSED".to_string() } else { format!("RUNNING {}x", run_speed) };
+        let wait = match key_wait {
+            KeyWait::Idle => String::new(),
+            KeyWait::Press => "Waiting for a key press".to_string(),
+            KeyWait::Release(key) => format!("Waiting for key {:X} to be released", key),
+        };
+        let audio_error = self.status.lock().unwrap().audio_error.clone().unwrap_or_default();
+        let fault = snapshot.fault.as_ref().map(|fault| fault.to_string());
+        let side_lines = [
+            format!("I:  0x{:04x}", ir),
+            format!("PC: 0x{:04x}", pc),
+            format!("DT: {:>3}", dt),
+            String::new(),
+            status,
+            wait,
+            audio_error,
+            fault.clone().unwrap_or_default(),
+            self.message.clone(),
+        ];
+        for (i, line) in side_lines.iter().enumerate() {
+            queue!(stdout, cursor::MoveTo(SIDE_PANE_COLUMN, (REGISTER_COUNT / 2 + 1 + i) as u16),
+                terminal::Clear(terminal::ClearType::UntilNewLine), style::Print(line))?;
+        }
+
+        // disassembly
+        let top = SCREEN_HEIGHT as u16 / 2 + 2;
+        for (i, line) in disassembly.iter().enumerate() {
+            queue!(stdout, cursor::MoveTo(1, top + i as u16),
+                terminal::Clear(terminal::ClearType::UntilNewLine), style::Print(line))?;
+        }
+        let help = if fault.is_some() { FAULT_HELP_TEXT } else { HELP_TEXT };
+        queue!(stdout, cursor::MoveTo(1, top + disassembly.len() as u16 + 1),
+            terminal::Clear(terminal::ClearType::UntilNewLine), style::Print(help))?;
+
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+// Draws the display as text, two pixels per character using half blocks.
+pub fn display_lines(frame_buffer: &FrameBuffer) -> Vec<String> {
+    (0..SCREEN_HEIGHT / 2).map(|row| {
+        (0..SCREEN_WIDTH).map(|x| {
+            match (get_pixel(frame_buffer, x, row * 2), get_pixel(frame_buffer, x, row * 2 + 1)) {
+                (false, false) => ' ',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (true, true) => '█',
+            }
+        }).collect()
+    }).collect()
+}
@@ -0,0 +1,62 @@
+// Noticing when the loaded ROM changes on disk, so it can be reloaded
+// straight after being assembled again.
+
+use std::{fs, path::{Path, PathBuf}, time::{Duration, Instant, SystemTime}};
+
+// How often the file is checked
+const POLL_TIME: Duration = Duration::from_millis(500);
+
+pub struct RomWatcher {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,   // when the file was last changed, as of the last check
+    last_poll: Option<Instant>
+}
+
+impl RomWatcher {
+    pub fn new() -> Self {
+        RomWatcher {
+            path: None,
+            modified: None,
+            last_poll: None
+        }
+    }
+
+    // Returns true if the file at `path` has changed since the last call.
+    // Switching to a different file doesn't count as a change.
+    pub fn poll(&mut self, path: &str) -> bool {
+        let path = Path::new(path);
+        if self.path.as_deref() != Some(path) {
+            self.path = Some(path.to_path_buf());
+            self.modified = modified_time(path);
+            self.last_poll = Some(Instant::now());
+            return false;
+        }
+        if self.last_poll.is_some_and(|last| last.elapsed() < POLL_TIME) {
+            return false;
+        }
+        self.last_poll = Some(Instant::now());
+
+        let modified = modified_time(path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+
+    // Forgets the file, for when watching is turned off, so changes made in
+    // the meantime don't count once it's back on.
+    pub fn stop(&mut self) {
+        *self = RomWatcher::new();
+    }
+}
+
+// Modification time of a file, or None if it's missing or empty, which
+// happens for a moment while some editors save.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.len() == 0 {
+        return None;
+    }
+    metadata.modified().ok()
+}
@@ -0,0 +1,93 @@
+// Minimal writer for mono 16-bit PCM WAV files.
+
+use std::{fs::File, io::{self, BufWriter, Seek, SeekFrom, Write}, path::Path};
+
+const BITS_PER_SAMPLE: u16 = 16;
+const BYTES_PER_SAMPLE: u32 = BITS_PER_SAMPLE as u32 / 8;
+const HEADER_SIZE: u32 = 44;
+
+// Writes a WAV file bit by bit. The header is kept up to date after every
+// write, so the file is valid even if the program exits without warning.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    data_len: u32
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;                           // chunk size
+        out.write_all(&1u16.to_le_bytes())?;                            // PCM
+        out.write_all(&1u16.to_le_bytes())?;                            // channels
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * BYTES_PER_SAMPLE).to_le_bytes())?; // byte rate
+        out.write_all(&(BYTES_PER_SAMPLE as u16).to_le_bytes())?;       // block align
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.flush()?;
+
+        Ok(WavWriter {
+            out,
+            data_len: 0
+        })
+    }
+
+    // Appends samples in the range -1 to 1.
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.out.write_all(&val.to_le_bytes())?;
+        }
+        self.data_len += samples.len() as u32 * BYTES_PER_SAMPLE;
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+// Writes samples in the range -1 to 1 to a WAV file.
+pub fn write_wav(path: &Path, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    WavWriter::create(path, sample_rate)?.write_samples(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_matches_samples_written() {
+        let path = env::temp_dir().join(format!("chip8-wav-test-{}.wav", process::id()));
+        let mut writer = WavWriter::create(&path, 44100).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0]).unwrap();
+        writer.write_samples(&[0.5; 100]).unwrap();
+        drop(writer);
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let data_len = 103 * BYTES_PER_SAMPLE;
+        assert_eq!(bytes.len(), (HEADER_SIZE + data_len) as usize);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(read_u32(&bytes, 4), HEADER_SIZE - 8 + data_len);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(read_u32(&bytes, 40), data_len);
+        assert_eq!(read_u32(&bytes, 24), 44100);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), i16::MAX);
+    }
+}
@@ -0,0 +1,24 @@
+#!/bin/sh
+# Downloads the test ROMs from Timendus' chip8-test-suite (MIT licensed) into
+# tests/roms, for the conformance tests. BC_test.ch8 has no licence to speak
+# of, so it has to be added by hand.
+#
+# Pinned to a release so the golden images stay valid; set
+# CHIP8_TEST_SUITE_REF to use another tag or branch, then make new ones
+# as described in src/conformance.rs.
+
+set -eu
+
+REF="${CHIP8_TEST_SUITE_REF:-v4.1}"
+BASE="https://raw.githubusercontent.com/Timendus/chip8-test-suite/$REF/bin"
+DIR="$(dirname "$0")/roms"
+
+mkdir -p "$DIR"
+for rom in 2-ibm-logo.ch8 3-corax+.ch8 4-flags.ch8 5-quirks.ch8 6-keypad.ch8; do
+    echo "Fetching $rom"
+    curl -fsSL -o "$DIR/$rom" "$BASE/$rom"
+done
+
+if [ ! -f "$DIR/BC_test.ch8" ]; then
+    echo "BC_test.ch8 isn't in $DIR, add it by hand or its tests are skipped"
+fi